@@ -0,0 +1,156 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Wake, Waker},
+    thread::Thread,
+    time::Duration,
+};
+
+use clap::CommandFactory as _;
+use cmd3::{
+    async_console::{AsyncCommand, AsyncConsole, CommandFuture},
+    console::{Command, Console, ExecContext},
+};
+
+/// Wait for the given number of milliseconds, then write `done`.
+#[derive(clap::Parser, Debug)]
+struct SleepArgs {
+    /// Milliseconds to sleep for
+    millis: u64,
+}
+
+struct SleepCommand;
+
+impl AsyncCommand for SleepCommand {
+    fn get_name(&self) -> String {
+        "sleep".to_string()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        SleepArgs::command()
+    }
+
+    fn execute<'a>(
+        &'a self,
+        args: clap::ArgMatches,
+        ctx: &'a mut ExecContext<'_>,
+    ) -> CommandFuture<'a> {
+        Box::pin(async move {
+            let args: SleepArgs = clap::FromArgMatches::from_arg_matches(&args)?;
+            Delay::new(Duration::from_millis(args.millis)).await;
+            writeln!(ctx.stdout, "done")?;
+            Ok(())
+        })
+    }
+}
+
+/// Uppercases stdin.
+#[derive(clap::Parser, Debug)]
+struct UpperArgs;
+
+struct UpperCommand;
+
+impl Command for UpperCommand {
+    fn get_name(&self) -> String {
+        "upper".to_string()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        UpperArgs::command()
+    }
+
+    fn execute(
+        &self,
+        _args: clap::ArgMatches,
+        ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        write!(ctx.stdout, "{}", ctx.stdin.to_uppercase())?;
+        Ok(())
+    }
+}
+
+/// A timer that completes on a background thread. With a real runtime, use
+/// its own timer (e.g. `tokio::time::sleep`) instead.
+struct Delay {
+    duration: Duration,
+    state: Option<Arc<Mutex<DelayState>>>,
+}
+
+#[derive(Default)]
+struct DelayState {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+impl Delay {
+    fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            state: None,
+        }
+    }
+}
+
+impl Future for Delay {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let duration = self.duration;
+        let state = self.state.get_or_insert_with(|| {
+            let state = Arc::new(Mutex::new(DelayState::default()));
+            let thread_state = Arc::clone(&state);
+            std::thread::spawn(move || {
+                std::thread::sleep(duration);
+                let mut state = thread_state.lock().unwrap();
+                state.done = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+            state
+        });
+
+        let mut state = state.lock().unwrap();
+        if state.done {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// A minimal executor, standing in for e.g. `tokio::runtime::Runtime::block_on`.
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = std::pin::pin!(future);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => std::thread::park(),
+        }
+    }
+}
+
+fn main() {
+    let mut console = AsyncConsole::new(Console::default().add_command(Box::new(UpperCommand)))
+        .add_async_command(Box::new(SleepCommand));
+
+    match block_on(console.cmd_loop()) {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
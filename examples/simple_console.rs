@@ -1,5 +1,5 @@
 use clap::CommandFactory as _;
-use cmd3::console::{Command, Console};
+use cmd3::console::{Command, Console, ExecContext};
 
 /// Write `arg`s separated by a single space and followed by a newline.
 #[derive(clap::Parser, Debug)]
@@ -23,18 +23,21 @@ impl Command for EchoCommand {
         EchoArgs::command()
     }
 
+    fn usage_hint(&self) -> Option<String> {
+        Some("usage: echo [-n] [arg]...".to_string())
+    }
+
     fn execute(
         &self,
         args: clap::ArgMatches,
-        _stdin: &str,
-        stdout: &mut dyn std::fmt::Write,
+        ctx: &mut ExecContext<'_>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let args: EchoArgs = clap::FromArgMatches::from_arg_matches(&args).unwrap();
 
         if args.no_newline {
-            write!(stdout, "{}", args.arg.join(" "))?;
+            write!(ctx.stdout, "{}", args.arg.join(" "))?;
         } else {
-            writeln!(stdout, "{}", args.arg.join(" "))?;
+            writeln!(ctx.stdout, "{}", args.arg.join(" "))?;
         }
 
         Ok(())
@@ -45,34 +48,41 @@ impl Command for EchoCommand {
 #[derive(clap::Parser, Debug)]
 struct UpperArgs;
 
-struct UpperCommand;
-
-impl Command for UpperCommand {
-    fn get_name(&self) -> String {
-        "upper".to_string()
-    }
-
-    fn get_parser(&self) -> clap::Command {
-        UpperArgs::command()
-    }
-
-    fn execute(
-        &self,
-        _args: clap::ArgMatches,
-        stdin: &str,
-        stdout: &mut dyn std::fmt::Write,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        write!(stdout, "{}", stdin.to_uppercase())?;
-        Ok(())
-    }
-}
-
 fn main() {
     let mut console = Console::default()
         .add_command(Box::new(EchoCommand {}))
-        .add_command(Box::new(UpperCommand {}));
+        // Simple commands can be defined without implementing `Command`
+        .add_command(Box::new(cmd3::command!(
+            "upper",
+            UpperArgs,
+            |_args, ctx| {
+                if ctx.stdin.is_empty() {
+                    writeln!(ctx.stderr, "upper: warning: no input")?;
+                }
+
+                write!(ctx.stdout, "{}", ctx.stdin.to_uppercase())?;
+                Ok(())
+            }
+        )));
 
-    if let Err(e) = console.cmd_loop() {
-        eprintln!("{e}");
+    // Run a script non-interactively if one is given, e.g.
+    // `cargo run --example simple_console -- script.txt`
+    let result = match std::env::args().nth(1) {
+        Some(path) => match std::fs::File::open(&path) {
+            Ok(file) => console.run_script(std::io::BufReader::new(file)),
+            Err(e) => {
+                eprintln!("Could not open `{path}`: {e}");
+                std::process::exit(1);
+            }
+        },
+        None => console.cmd_loop(),
+    };
+
+    match result {
+        Ok(status) => std::process::exit(status),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
     }
 }
@@ -0,0 +1,260 @@
+//! Commands that run asynchronously, e.g. on a tokio runtime.
+//!
+//! An [`AsyncConsole`] wraps a [`Console`] and awaits each stage of a pipeline,
+//! so both ordinary and asynchronous commands can be used in the same pipeline.
+//! The console doesn't depend on any particular runtime.
+
+use std::{collections::VecDeque, future::Future, pin::Pin, time::Instant};
+
+use crate::console::{
+    report_timings, strip_null_redirect, Command, Console, ConsoleError, ExecContext, Parsed,
+    Runnable, StderrWriter,
+};
+
+/// The future returned by [`AsyncCommand::execute`].
+pub type CommandFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<(), Box<dyn std::error::Error>>> + 'a>>;
+
+/// The asynchronous counterpart of [`Command`]. `execute` can't be an `async
+/// fn` since the command is used as a trait object, so implementations return
+/// a boxed future instead, usually `Box::pin(async move { ... })`.
+pub trait AsyncCommand {
+    fn get_name(&self) -> String;
+
+    fn get_parser(&self) -> clap::Command;
+
+    /// The category this command is listed under by the built-in `help`.
+    fn category(&self) -> Option<String> {
+        None
+    }
+
+    /// See [`Command::usage_hint`].
+    fn usage_hint(&self) -> Option<String> {
+        None
+    }
+
+    /// See [`Command::long_help`].
+    fn long_help(&self) -> Option<String> {
+        None
+    }
+
+    /// Run the command. See [`Command::execute`].
+    fn execute<'a>(
+        &'a self,
+        args: clap::ArgMatches,
+        ctx: &'a mut ExecContext<'_>,
+    ) -> CommandFuture<'a>;
+}
+
+/// Registers an [`AsyncCommand`] with a [`Console`], so it is completed and
+/// listed in help like any other command.
+struct AsyncAdapter(Box<dyn AsyncCommand>);
+
+impl Command for AsyncAdapter {
+    fn get_name(&self) -> String {
+        self.0.get_name()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        self.0.get_parser()
+    }
+
+    fn category(&self) -> Option<String> {
+        self.0.category()
+    }
+
+    fn usage_hint(&self) -> Option<String> {
+        self.0.usage_hint()
+    }
+
+    fn long_help(&self) -> Option<String> {
+        self.0.long_help()
+    }
+
+    fn execute(
+        &self,
+        _args: clap::ArgMatches,
+        _ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("this command is asynchronous and can only be run by an `AsyncConsole`".into())
+    }
+
+    fn as_async(&self) -> Option<&dyn AsyncCommand> {
+        Some(self.0.as_ref())
+    }
+}
+
+pub struct AsyncConsole {
+    console: Console,
+}
+
+impl AsyncConsole {
+    pub fn new(console: Console) -> Self {
+        Self { console }
+    }
+
+    pub fn add_async_command(self, cmd: Box<dyn AsyncCommand>) -> Self {
+        Self {
+            console: self.console.add_command(Box::new(AsyncAdapter(cmd))),
+        }
+    }
+
+    pub fn console(&self) -> &Console {
+        &self.console
+    }
+
+    pub fn console_mut(&mut self) -> &mut Console {
+        &mut self.console
+    }
+
+    /// Run the interactive loop until EOF, like [`Console::cmd_loop`].
+    ///
+    /// The line editor shares the console's (non-`Send`) command set for
+    /// completion, so it can't be moved to a blocking thread. Reading a line
+    /// therefore blocks the current thread, and this future is best run on a
+    /// runtime or thread dedicated to the console, e.g. a tokio `LocalSet`.
+    pub async fn cmd_loop(&mut self) -> Result<i32, ConsoleError> {
+        let mut rl = self.console.create_editor()?;
+        self.console.prepare_editor(&mut rl)?;
+        self.console.set_interactive(true);
+        let result = async {
+            for line in self.console.startup_commands().to_vec() {
+                let result = self.run_line(&line).await;
+                self.console.report_line_error(result)?;
+                if let Some(status) = self.console.startup_stop() {
+                    return Ok(status);
+                }
+            }
+
+            while let Some(line) = self.console.next_line(&mut rl)? {
+                let result = self.run_line(&line).await;
+                self.console.report_line_error(result)?;
+            }
+
+            Ok(0)
+        }
+        .await;
+        self.console.exit();
+
+        result
+    }
+
+    /// Parse and run a single command line, awaiting each stage in turn. See
+    /// [`Console::run_line`].
+    ///
+    /// Command substitutions like `$(...)` are run synchronously, so they can
+    /// only use ordinary commands.
+    // The command set is borrowed for the whole line, as in the synchronous
+    // console. Nothing else can mutate it meanwhile, since registering a
+    // command needs the console itself.
+    #[allow(clippy::await_holding_refcell_ref)]
+    pub async fn run_line(&mut self, line: &str) -> Result<(), ConsoleError> {
+        let line = self.console.rewrite_line(line);
+        let line = match self.console.substitute_commands(&line) {
+            Ok(line) => line,
+            Err(e) => return self.console.record_status(Err(e)),
+        };
+        self.console.trace_line(&line);
+        let (line, print) = match strip_null_redirect(&line, self.console.backslash()) {
+            Some(rest) => (rest.to_string(), false),
+            None => (line, true),
+        };
+
+        let result = {
+            let commands = self.console.command_set();
+            let command_set = commands.borrow();
+
+            match self.console.parse_pipeline(&command_set, &line) {
+                Ok(Parsed::Pipeline(runnables)) => self.run_runnables(runnables, print).await,
+                Ok(Parsed::Finished(status)) => Ok(status),
+                Err(e) => Err(e),
+            }
+        };
+        self.console.apply_pending_control();
+
+        self.console.record_status(result)
+    }
+
+    async fn run_runnables(
+        &mut self,
+        mut runnables: VecDeque<Runnable<'_>>,
+        print: bool,
+    ) -> Result<i32, ConsoleError> {
+        let in_pipeline = runnables.len() > 1;
+
+        let mut timings = vec![];
+        let mut previous_output = String::new();
+        let mut stage = 0;
+        while let Some(runnable) = runnables.pop_front() {
+            stage += 1;
+            let mut output_buf = String::new();
+            let start = self.console.timing().then(Instant::now);
+            // Async commands are always buffered
+            let live = print
+                && runnables.is_empty()
+                && match &runnable {
+                    Runnable::Command { cmd, .. } => cmd.as_async().is_none() && cmd.live_output(),
+                    _ => false,
+                };
+            let (res, command_name) = match runnable {
+                Runnable::Command { cmd, args } if live => (
+                    self.console.run_live_stage(cmd, args, &previous_output),
+                    cmd.get_name(),
+                ),
+                Runnable::Command { cmd, args } => match cmd.as_async() {
+                    Some(cmd) => {
+                        let mut stderr = StderrWriter;
+                        let mut ctx = self.console.exec_context(
+                            &previous_output,
+                            &mut output_buf,
+                            &mut stderr,
+                        );
+                        (cmd.execute(args, &mut ctx).await, cmd.get_name())
+                    }
+                    None => self.console.run_stage(
+                        Runnable::Command { cmd, args },
+                        &previous_output,
+                        &mut output_buf,
+                    ),
+                },
+                runnable => self
+                    .console
+                    .run_stage(runnable, &previous_output, &mut output_buf),
+            };
+            if let Some(start) = start {
+                timings.push((command_name.clone(), start.elapsed()));
+            }
+            let output = self.console.finish_stage(
+                res,
+                command_name,
+                in_pipeline.then_some(stage),
+                output_buf,
+            );
+            match output? {
+                // Live output has already been printed, unfiltered and untransformed
+                Some(_) if live => {
+                    report_timings(&timings);
+                    return Ok(0);
+                }
+                Some(output) => previous_output = output,
+                None => {
+                    report_timings(&timings);
+                    return Ok(0);
+                }
+            }
+        }
+        report_timings(&timings);
+
+        if print {
+            self.console.print_output(&previous_output)?;
+        }
+
+        Ok(0)
+    }
+}
+
+impl From<Console> for AsyncConsole {
+    fn from(console: Console) -> Self {
+        Self::new(console)
+    }
+}
@@ -0,0 +1,313 @@
+//! Ready-made commands for common text processing, registered all at once
+//! with [`Console::with_builtins`](crate::console::Console::with_builtins).
+//! With the `clipboard` feature, this includes `copy`.
+
+use crate::console::{Command, ExecContext};
+
+/// Every built-in command.
+pub fn all() -> Vec<Box<dyn Command>> {
+    vec![
+        Box::new(Echo),
+        Box::new(Cat),
+        Box::new(Grep),
+        Box::new(Head),
+        #[cfg(feature = "clipboard")]
+        Box::new(CopyToClipboard),
+    ]
+}
+
+/// The contents of each of the `file` arguments concatenated, or `None` if
+/// there are none and stdin should be read instead. Relative paths are in the
+/// console's current directory.
+fn read_files(
+    args: &clap::ArgMatches,
+    ctx: &ExecContext<'_>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let Some(files) = args.get_many::<String>("file") else {
+        return Ok(None);
+    };
+
+    let mut contents = String::new();
+    for file in files {
+        let text = std::fs::read_to_string(ctx.current_dir.join(file))
+            .map_err(|e| format!("{file}: {e}"))?;
+        contents.push_str(&text);
+    }
+    Ok(Some(contents))
+}
+
+fn file_arg() -> clap::Arg {
+    clap::Arg::new("file")
+        .help("Files to read instead of stdin")
+        .num_args(1..)
+}
+
+/// Write its arguments separated by spaces.
+pub struct Echo;
+
+impl Command for Echo {
+    fn get_name(&self) -> String {
+        "echo".to_string()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        clap::Command::new("echo")
+            .about("Write arguments separated by a single space")
+            .arg(
+                clap::Arg::new("arg")
+                    .help("Arguments to write")
+                    .num_args(0..)
+                    .allow_hyphen_values(true),
+            )
+            .arg(
+                clap::Arg::new("no_newline")
+                    .short('n')
+                    .help("Do not append a newline")
+                    .action(clap::ArgAction::SetTrue),
+            )
+    }
+
+    fn execute(
+        &self,
+        args: clap::ArgMatches,
+        ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let words: Vec<&str> = args
+            .get_many::<String>("arg")
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect();
+        write!(ctx.stdout, "{}", words.join(" "))?;
+        if !args.get_flag("no_newline") {
+            writeln!(ctx.stdout)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the contents of files, or stdin.
+pub struct Cat;
+
+impl Command for Cat {
+    fn get_name(&self) -> String {
+        "cat".to_string()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        clap::Command::new("cat")
+            .about("Concatenate files, or pass stdin through")
+            .arg(file_arg())
+    }
+
+    fn execute(
+        &self,
+        args: clap::ArgMatches,
+        ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = read_files(&args, ctx)?;
+        write!(ctx.stdout, "{}", contents.as_deref().unwrap_or(ctx.stdin))?;
+        Ok(())
+    }
+}
+
+/// Write the lines containing a pattern.
+pub struct Grep;
+
+impl Command for Grep {
+    fn get_name(&self) -> String {
+        "grep".to_string()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        clap::Command::new("grep")
+            .about("Write the lines that contain a pattern")
+            .arg(
+                clap::Arg::new("pattern")
+                    .help("Text to search for")
+                    .required(true),
+            )
+            .arg(file_arg())
+            .arg(
+                clap::Arg::new("ignore_case")
+                    .short('i')
+                    .long("ignore-case")
+                    .help("Ignore case when matching")
+                    .action(clap::ArgAction::SetTrue),
+            )
+            .arg(
+                clap::Arg::new("invert")
+                    .short('v')
+                    .long("invert-match")
+                    .help("Write the lines that don't match instead")
+                    .action(clap::ArgAction::SetTrue),
+            )
+    }
+
+    fn execute(
+        &self,
+        args: clap::ArgMatches,
+        ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let ignore_case = args.get_flag("ignore_case");
+        let invert = args.get_flag("invert");
+        let mut pattern = args
+            .get_one::<String>("pattern")
+            .cloned()
+            .unwrap_or_default();
+        if ignore_case {
+            pattern = pattern.to_lowercase();
+        }
+
+        let contents = read_files(&args, ctx)?;
+        let lines = contents.as_deref().unwrap_or(ctx.stdin).lines();
+        for line in lines {
+            let matched = if ignore_case {
+                line.to_lowercase().contains(&pattern)
+            } else {
+                line.contains(&pattern)
+            };
+            if matched != invert {
+                writeln!(ctx.stdout, "{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write the first lines of files, or stdin.
+pub struct Head;
+
+impl Command for Head {
+    fn get_name(&self) -> String {
+        "head".to_string()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        clap::Command::new("head")
+            .about("Write the first lines of files, or stdin")
+            .arg(file_arg())
+            .arg(
+                clap::Arg::new("lines")
+                    .short('n')
+                    .long("lines")
+                    .help("Number of lines to write")
+                    .value_parser(clap::value_parser!(usize))
+                    .default_value("10"),
+            )
+    }
+
+    fn execute(
+        &self,
+        args: clap::ArgMatches,
+        ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let count = args.get_one::<usize>("lines").copied().unwrap_or(10);
+        let contents = read_files(&args, ctx)?;
+        let lines = contents.as_deref().unwrap_or(ctx.stdin).lines();
+        for line in lines.take(count) {
+            writeln!(ctx.stdout, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Put stdin on the system clipboard, as the last stage of a pipeline, e.g.
+/// `echo hi | copy`.
+#[cfg(feature = "clipboard")]
+pub struct CopyToClipboard;
+
+#[cfg(feature = "clipboard")]
+impl CopyToClipboard {
+    /// The programs that can set the clipboard on this platform, with their
+    /// arguments, in the order they're tried.
+    fn tools() -> &'static [(&'static str, &'static [&'static str])] {
+        if cfg!(target_os = "macos") {
+            &[("pbcopy", &[])]
+        } else if cfg!(windows) {
+            &[("clip", &[])]
+        } else {
+            &[
+                ("wl-copy", &[]),
+                ("xclip", &["-selection", "clipboard"]),
+                ("xsel", &["--clipboard", "--input"]),
+            ]
+        }
+    }
+}
+
+#[cfg(feature = "clipboard")]
+impl Command for CopyToClipboard {
+    fn get_name(&self) -> String {
+        "copy".to_string()
+    }
+
+    fn get_parser(&self) -> clap::Command {
+        clap::Command::new("copy")
+            .about("Put stdin on the system clipboard")
+            .arg(
+                clap::Arg::new("echo")
+                    .short('e')
+                    .long("echo")
+                    .help("Also write what was copied")
+                    .action(clap::ArgAction::SetTrue),
+            )
+    }
+
+    fn execute(
+        &self,
+        args: clap::ArgMatches,
+        ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        use std::{io::Write as _, process::Stdio};
+
+        for (program, tool_args) in Self::tools() {
+            let child = std::process::Command::new(program)
+                .args(*tool_args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("{program}: {e}").into()),
+            };
+
+            // Dropping stdin after writing signals EOF
+            let written = child
+                .stdin
+                .take()
+                .map_or(Ok(()), |mut stdin| stdin.write_all(ctx.stdin.as_bytes()));
+            let status = child.wait()?;
+            written.map_err(|e| format!("{program}: {e}"))?;
+            if !status.success() {
+                return Err(format!("{program} failed with {status}").into());
+            }
+
+            if args.get_flag("echo") {
+                write!(ctx.stdout, "{}", ctx.stdin)?;
+            }
+            return Ok(());
+        }
+
+        let tried: Vec<&str> = Self::tools().iter().map(|(program, _)| *program).collect();
+        Err(format!("no clipboard tool found (tried {})", tried.join(", ")).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::console::Console;
+
+    #[test]
+    fn builtins_form_pipelines() {
+        let mut console = Console::default().with_builtins();
+
+        let output = console
+            .capture_line("echo -n 'a b\nc\nAB' | grep -i b | head -n 1")
+            .unwrap();
+        assert_eq!(output, "a b\n");
+        let output = console.capture_line("echo x | cat | grep -v x").unwrap();
+        assert_eq!(output, "");
+    }
+}
@@ -1,16 +1,217 @@
-use std::collections::VecDeque;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
+use clap::{ArgAction, ValueHint};
+use colored::Colorize;
 use rustyline::completion::{Completer, Pair};
 
-use crate::console::CommandSet;
+use crate::{
+    console::{find_command, AliasSet, Command, CommandSet, CompletionFilter},
+    expand,
+    intrinsic::{self, Intrinsic},
+    lexer::{self, PartialWord, Word},
+};
 
 pub(crate) struct CommandCompleter {
     commands: CommandSet,
+    aliases: AliasSet,
+    case_insensitive: bool,
+    show_help: bool,
+    externals: bool,
+    external_allowlist: Option<HashSet<String>>,
+    filter: Option<Rc<CompletionFilter>>,
+    /// The directory relative paths are completed in, changed with `cd`.
+    current_dir: Rc<RefCell<PathBuf>>,
+    /// The names of the session variables, completed after `$`.
+    vars: Rc<RefCell<Vec<String>>>,
+    /// The executables found in `PATH`, and the value of `PATH` they were
+    /// found with. Scanning every directory is too slow to do on every tab.
+    path_executables: RefCell<Option<(OsString, Vec<String>)>>,
 }
 
 impl CommandCompleter {
     pub fn new(commands: CommandSet) -> Self {
-        Self { commands }
+        Self {
+            commands,
+            aliases: AliasSet::default(),
+            case_insensitive: false,
+            show_help: false,
+            externals: true,
+            external_allowlist: None,
+            filter: None,
+            current_dir: Rc::new(RefCell::new(PathBuf::from("."))),
+            vars: Rc::default(),
+            path_executables: RefCell::new(None),
+        }
+    }
+
+    /// Complete the names of the aliases in `aliases` too.
+    pub fn with_aliases(mut self, aliases: AliasSet) -> Self {
+        self.aliases = aliases;
+        self
+    }
+
+    /// Complete relative paths in the directory `current_dir` holds, rather
+    /// than the process's current directory.
+    pub fn with_current_dir(mut self, current_dir: Rc<RefCell<PathBuf>>) -> Self {
+        self.current_dir = current_dir;
+        self
+    }
+
+    /// Complete the names of the variables in `vars` after `$`.
+    pub fn with_vars(mut self, vars: Rc<RefCell<Vec<String>>>) -> Self {
+        self.vars = vars;
+        self
+    }
+
+    /// Match command names regardless of case.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Show each argument's help text next to its completion.
+    pub fn with_help(mut self, show_help: bool) -> Self {
+        self.show_help = show_help;
+        self
+    }
+
+    /// Complete the names of external commands after `!`.
+    pub fn with_externals(mut self, externals: bool) -> Self {
+        self.externals = externals;
+        self
+    }
+
+    /// Only complete the external commands in `allowlist`, if any.
+    pub fn with_external_allowlist(mut self, allowlist: Option<HashSet<String>>) -> Self {
+        self.external_allowlist = allowlist;
+        self
+    }
+
+    /// Reorder or remove the candidates of each completion with `filter`.
+    pub fn with_filter(mut self, filter: Option<Rc<CompletionFilter>>) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Append the help text of each completion's argument to its display, if
+    /// enabled, lining the help texts up in a column. The replacements are
+    /// left unchanged.
+    fn add_help(&self, completions: Vec<(Pair, &clap::Arg)>) -> Vec<Pair> {
+        if !self.show_help {
+            return completions.into_iter().map(|(pair, _)| pair).collect();
+        }
+
+        let width = completions
+            .iter()
+            .map(|(pair, _)| pair.display.chars().count())
+            .max()
+            .unwrap_or_default();
+        completions
+            .into_iter()
+            .map(|(mut pair, arg)| {
+                if let Some(help) = arg.get_help() {
+                    pair.display = format!("{:width$}  {help}", pair.display);
+                }
+                pair
+            })
+            .collect()
+    }
+
+    /// Complete the names of `parser`'s subcommands, and their visible
+    /// aliases, that start with `prefix`. Aliases are dimmed, and followed by
+    /// the name they stand for.
+    fn complete_subcommands(&self, parser: &clap::Command, prefix: &str) -> Vec<Pair> {
+        // Each completion, with the width of its display without any color
+        // and the help text of its subcommand
+        let mut completions = vec![];
+        for subcommand in parser.get_subcommands() {
+            let name = subcommand.get_name();
+            let about = subcommand.get_about().map(|about| about.to_string());
+            if name.starts_with(prefix) {
+                let pair = Pair {
+                    display: name.to_string(),
+                    replacement: name.to_string(),
+                };
+                completions.push((pair, name.chars().count(), about.clone()));
+            }
+            for alias in subcommand.get_visible_aliases() {
+                if alias.starts_with(prefix) {
+                    let display = format!("{alias} ({name})");
+                    let pair = Pair {
+                        display: format!("{} ({name})", alias.dimmed()),
+                        replacement: alias.to_string(),
+                    };
+                    completions.push((pair, display.chars().count(), about.clone()));
+                }
+            }
+        }
+
+        let width = completions
+            .iter()
+            .map(|(_, width, _)| *width)
+            .max()
+            .unwrap_or_default();
+        completions
+            .into_iter()
+            .map(|(mut pair, display_width, about)| {
+                if let Some(about) = about.filter(|_| self.show_help) {
+                    let padding = " ".repeat(width - display_width);
+                    pair.display = format!("{}{padding}  {about}", pair.display);
+                }
+                pair
+            })
+            .collect()
+    }
+
+    /// Complete the name of an external command, found in `PATH`.
+    fn complete_external(&self, prefix: &str) -> Vec<Pair> {
+        if !self.externals {
+            return vec![];
+        }
+
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut cache = self.path_executables.borrow_mut();
+        if cache
+            .as_ref()
+            .is_none_or(|(cached_path, _)| *cached_path != path)
+        {
+            *cache = Some((path, intrinsic::executables_in_path()));
+        }
+
+        let executables = cache.as_ref().map(|(_, names)| names.as_slice());
+        executables
+            .unwrap_or_default()
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .filter(|name| {
+                self.external_allowlist
+                    .as_ref()
+                    .is_none_or(|allowlist| allowlist.contains(*name))
+            })
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: format!("!{name}"),
+            })
+            .collect()
+    }
+
+    /// Complete the name of a session variable, keeping the `$` before it.
+    fn complete_var(&self, prefix: &str) -> Vec<Pair> {
+        self.vars
+            .borrow()
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .map(|name| Pair {
+                display: name.clone(),
+                replacement: format!("${name}"),
+            })
+            .collect()
     }
 }
 
@@ -23,94 +224,302 @@ impl Completer for CommandCompleter {
         pos: usize,
         _ctx: &rustyline::Context<'_>,
     ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, mut candidates) = self.complete_line(line, pos)?;
+        if let Some(filter) = &self.filter {
+            filter(&mut candidates);
+        }
+        Ok((start, candidates))
+    }
+}
+
+impl CommandCompleter {
+    /// Complete the word before `pos` in `line`, returning where it starts and
+    /// its candidates.
+    fn complete_line(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<Pair>)> {
+        log::trace!("completing {line:?} at {pos}");
+        // Positions are byte offsets, which rustyline keeps on character
+        // boundaries, but a position from elsewhere may not be
+        if !line.is_char_boundary(pos) {
+            return Ok((pos, vec![]));
+        }
+
         let orig_pos = pos;
-        let (line, pos) = if let Some(i) = line.rfind('|') {
+        // Only the stage of the pipeline the cursor is in is completed
+        let (line, pos) = if let Some(i) = line[0..pos].rfind('|') {
             (&line[i + 1..], pos - i - 1)
         } else {
             (line, pos)
         };
+        // The word being completed, which starts where it was typed, though
+        // its text may be shorter once its quotes and escapes are removed
+        let partial = lexer::partial_word(&line[0..pos]);
+        let word_start = orig_pos - pos + partial.start;
+        let in_quote = partial.open_quote.is_some();
 
         let mut subtokens = VecDeque::from(match shlex::split(&line[0..pos]) {
             Some(o) => o,
-            None => return Ok((pos, vec![])),
+            None => {
+                // The cursor is inside an open quote. Only an argument, not
+                // the command name, can be completed there.
+                let before = shlex::split(&line[0..partial.start])
+                    .filter(|words| in_quote && !words.is_empty());
+                let Some(mut words) = before else {
+                    return Ok((orig_pos, vec![]));
+                };
+                words.push(partial.text.clone());
+                words
+            }
         });
+        // Whether the cursor is after a word, rather than on one
+        let at_blank = !in_quote && line[0..pos].ends_with(lexer::is_blank);
 
+        // Any whitespace after a `|` doesn't belong to the first word
+        let before_cursor = line[0..pos].trim_start_matches(lexer::is_blank);
         let (is_first_word, prefix) = if subtokens.is_empty() {
             (true, "")
         } else {
             (
-                subtokens.len() < 2 && !line[0..pos].contains(|o: char| o.is_whitespace()),
-                line.trim(),
+                subtokens.len() < 2 && !before_cursor.contains(lexer::is_blank),
+                before_cursor,
             )
         };
 
         let command_set = &self.commands.borrow();
 
-        if is_first_word {
+        // A quoted word is replaced from its opening quote, which would be lost
+        let var = partial
+            .text
+            .strip_prefix('$')
+            .filter(|_| !at_blank && !in_quote);
+        if let Some(var) = var {
+            Ok((word_start, self.complete_var(var)))
+        } else if let Some(program) = prefix.strip_prefix('!').filter(|_| is_first_word) {
+            // We are completing the name of an external command
+            Ok((word_start, self.complete_external(program)))
+        } else if is_first_word {
             // We are completing the name of a command
+            let intrinsics = Intrinsic::ALL
+                .iter()
+                .map(|i| i.name())
+                .filter(|name| !command_set.contains_key(*name));
+            let lowercase = prefix.to_lowercase();
+            let names: Vec<&str> = command_set
+                .keys()
+                .map(|k| k.as_str())
+                .chain(intrinsics)
+                // The typed prefix is replaced by the command's own name
+                .filter(|command| {
+                    if self.case_insensitive {
+                        command.to_lowercase().starts_with(&lowercase)
+                    } else {
+                        command.starts_with(prefix)
+                    }
+                })
+                .collect();
+
+            // Aliases that only rename one of those commands are noted next to
+            // it, rather than listed as well. Others that match are dimmed, and
+            // followed by what they stand for.
+            let aliases = self.aliases.borrow();
+            let mut renames: HashMap<&str, Vec<String>> = HashMap::new();
             let mut res = vec![];
-            for command in self.commands.borrow().keys() {
-                if command.starts_with(prefix) {
-                    res.push(Pair {
-                        display: command.to_string(),
-                        replacement: command.to_string(),
-                    });
+            for (alias, value) in aliases.iter() {
+                match names.iter().find(|&&name| name == value.trim()) {
+                    Some(name) => renames
+                        .entry(name)
+                        .or_default()
+                        .push(alias.dimmed().to_string()),
+                    None if alias.starts_with(prefix) => res.push(Pair {
+                        display: format!("{} ({value})", alias.dimmed()),
+                        replacement: alias.clone(),
+                    }),
+                    None => (),
                 }
             }
+            for name in names {
+                let display = match renames.get(name) {
+                    Some(aliases) => format!("{name} ({})", aliases.join(", ")),
+                    None => name.to_string(),
+                };
+                res.push(Pair {
+                    display,
+                    replacement: name.to_string(),
+                });
+            }
 
-            Ok((orig_pos.saturating_sub(line.len()), res))
+            Ok((word_start, res))
         } else {
             // We are completing an argument to a command
-            let command = match command_set.get(&subtokens.pop_front().unwrap_or_default()) {
-                Some(c) => c,
-                None => return Ok((orig_pos, vec![])), // Unrecognized command
+            let name = subtokens.pop_front().unwrap_or_default();
+            let command = find_command(command_set, &name, self.case_insensitive);
+            let mut parser = match command {
+                Some(c) if c.wants_raw_args() => return Ok((orig_pos, vec![])),
+                Some(c) => c.get_parser(),
+                None => match Intrinsic::find(&name, self.case_insensitive) {
+                    Some(intrinsic) => intrinsic.parser(),
+                    None => return Ok((orig_pos, vec![])), // Unrecognized command
+                },
             };
 
-            let mut completions: Vec<Pair> = vec![];
-            let parser = command.get_parser();
+            // Each completion, with the argument it's for
+            let mut completions: Vec<(Pair, &clap::Arg)> = vec![];
 
-            if line.chars().nth(pos - 1).unwrap().is_whitespace() {
-                // Cursor is not on a word, show all positional args
-                for arg in parser.get_positionals() {
-                    completions.push(Pair {
+            let mut typed = if at_blank {
+                subtokens.len()
+            } else {
+                subtokens.len().saturating_sub(1)
+            };
+
+            // Complete the arguments of the innermost subcommand typed so far,
+            // by its name or an alias
+            let mut i = 0;
+            while i < typed {
+                match parser.find_subcommand(&subtokens[i]).cloned() {
+                    Some(subcommand) => {
+                        parser = subcommand;
+                        subtokens.drain(..=i);
+                        typed -= i + 1;
+                        i = 0;
+                    }
+                    None => i += 1,
+                }
+            }
+
+            // Leave out arguments that can't be given with those already typed
+            let conflicts = conflicting_args(&parser, subtokens.iter().take(typed));
+            let allowed = |arg: &&clap::Arg| !conflicts.contains(arg.get_id());
+
+            if at_blank {
+                if parser.has_subcommands() {
+                    return Ok((orig_pos, self.complete_subcommands(&parser, "")));
+                }
+                if let Some(values) = complete_value(command, &parser, &subtokens, &partial) {
+                    return Ok((orig_pos, values));
+                }
+
+                // Cursor is not on a word, show the positional args that
+                // haven't been given all their values yet
+                let mut supplied = count_positionals(&parser, &subtokens);
+                for arg in parser.get_positionals().filter(allowed) {
+                    let capacity = positional_capacity(arg);
+                    if supplied >= capacity {
+                        supplied -= capacity;
+                        continue;
+                    }
+                    supplied = 0;
+
+                    let pair = Pair {
                         display: arg.get_id().to_string(),
                         replacement: "".to_string(), // Don't actually complete these metavars
-                    });
+                    };
+                    completions.push((pair, arg));
                 }
-                Ok((orig_pos, completions))
+                Ok((orig_pos, self.add_help(completions)))
             } else {
                 let word = subtokens.pop_back().unwrap();
 
-                if word.starts_with("--") {
+                if in_quote {
+                    // Only a value can be inside quotes
+                    let completions = complete_value(command, &parser, &subtokens, &partial)
+                        .unwrap_or_else(|| {
+                            complete_hinted(
+                                &parser,
+                                &subtokens,
+                                &partial,
+                                &self.current_dir.borrow(),
+                            )
+                        });
+                    Ok((word_start, completions))
+                } else if let Some((long, value)) = word
+                    .strip_prefix("--")
+                    .and_then(|option| option.split_once('='))
+                {
+                    // The value of a long option, as in `--level=high`
+                    if let Some(arg) = parser.get_opts().find(|arg| arg.get_long() == Some(long)) {
+                        for possible in arg.get_possible_values() {
+                            let name = possible.get_name();
+                            if name.starts_with(value) {
+                                let pair = Pair {
+                                    display: name.to_string(),
+                                    replacement: format!("--{long}={name} "),
+                                };
+                                completions.push((pair, arg));
+                            }
+                        }
+                    }
+                    Ok((word_start, self.add_help(completions)))
+                } else if word.starts_with("--") {
                     // Long form
-                    for arg in parser.get_opts() {
+                    for arg in parser.get_opts().filter(allowed) {
                         if let Some(long) = arg.get_long() {
                             // Only one possibility: long form
                             let replacement = format!("--{long}");
 
                             if replacement.starts_with(&word) {
-                                completions.push(Pair {
+                                let pair = Pair {
                                     display: format!("[{replacement}]"),
                                     replacement,
-                                });
+                                };
+                                completions.push((pair, arg));
                             }
                         }
                     }
-                    Ok((orig_pos - word.len(), completions))
+                    Ok((word_start, self.add_help(completions)))
+                } else if is_number(&word) && expects_value(&parser, subtokens.back()) {
+                    // A negative number given as a value, not a flag
+                    Ok((orig_pos, vec![]))
+                } else if let Some(bundle) = bundled_shorts(&parser, &word) {
+                    // Boolean short flags, alone or combined, e.g. `-ab`. Offer
+                    // the remaining ones that can be appended to the bundle.
+                    let in_bundle: Vec<String> = bundle.iter().map(|c| format!("-{c}")).collect();
+                    let words = subtokens.iter().take(typed).chain(&in_bundle);
+                    let conflicts = conflicting_args(&parser, words);
+                    let allowed = |arg: &&clap::Arg| !conflicts.contains(arg.get_id());
+                    for arg in parser.get_arguments().filter(allowed) {
+                        let short = match arg.get_short() {
+                            Some(short) if is_bundleable(arg) => short,
+                            _ => continue,
+                        };
+
+                        // Counted flags like `-vvv` may repeat
+                        if bundle.contains(&short) && !matches!(arg.get_action(), ArgAction::Count)
+                        {
+                            continue;
+                        }
+
+                        let display = match arg.get_long() {
+                            Some(long) => format!("[-{short}, --{long}]"),
+                            None => format!("[-{short}]"),
+                        };
+                        let pair = Pair {
+                            display,
+                            replacement: format!("{word}{short}"),
+                        };
+                        completions.push((pair, arg));
+                    }
+                    Ok((word_start, self.add_help(completions)))
                 } else if word.starts_with("-") {
                     // Short OR long form
-                    for arg in parser.get_opts() {
+                    for arg in parser.get_opts().filter(allowed) {
                         let long = arg.get_long();
                         let short = arg.get_short();
 
+                        // Only options that take a value are followed by a
+                        // space, ready for it
+                        let space = if arg.get_action().takes_values() {
+                            " "
+                        } else {
+                            ""
+                        };
+
                         // Can be any of long+short, long only, or short only
                         let (display, replacement) =
                             if let (Some(long), Some(short)) = (long, short) {
-                                (format!("[-{short}, --{long}]"), format!("-{short} "))
+                                (format!("[-{short}, --{long}]"), format!("-{short}{space}"))
                             } else if let Some(long) = long {
-                                (format!("[--{long}]"), format!("--{long} "))
+                                (format!("[--{long}]"), format!("--{long}{space}"))
                             } else if let Some(short) = short {
-                                (format!("[-{short}]"), format!("-{short} "))
+                                (format!("[-{short}]"), format!("-{short}{space}"))
                             } else {
                                 // Trying to use such an arg will be a runtime
                                 // error when the parser is invoked, but it
@@ -120,20 +529,870 @@ impl Completer for CommandCompleter {
                             };
 
                         if replacement.starts_with(&word) {
-                            completions.push(Pair {
+                            let pair = Pair {
                                 display,
                                 replacement,
-                            });
+                            };
+                            completions.push((pair, arg));
                         }
                     }
-                    Ok((orig_pos - word.len(), completions))
+                    Ok((word_start, self.add_help(completions)))
+                } else if parser.has_subcommands() {
+                    let completions = self.complete_subcommands(&parser, &word);
+                    Ok((word_start, completions))
                 } else {
-                    // Must be a positional arg, don't bother completing them
-                    // since their names are just metavars. Possibly implement
-                    // custom completers here?
-                    Ok((orig_pos, vec![]))
+                    // Must be a value, which is completed as its argument's
+                    // value hint suggests unless the command completes it
+                    // itself
+                    let completions = complete_value(command, &parser, &subtokens, &partial)
+                        .unwrap_or_else(|| {
+                            complete_hinted(
+                                &parser,
+                                &subtokens,
+                                &partial,
+                                &self.current_dir.borrow(),
+                            )
+                        });
+                    Ok((word_start, completions))
+                }
+            }
+        }
+    }
+}
+
+/// Complete the partial path `word` with the names of the files and
+/// directories it could be. Each replacement is the whole word, quoted the way
+/// it was typed: a quote that was left open is closed after the name of a file,
+/// but not of a directory, so that completion can continue inside it.
+/// Relative paths are completed in `current_dir`.
+fn complete_path(word: &PartialWord, current_dir: &Path) -> Vec<Pair> {
+    let (dir, prefix) = match word.text.rfind('/') {
+        Some(i) => word.text.split_at(i + 1),
+        None => ("", word.text.as_str()),
+    };
+    let search_dir = current_dir.join(expand::expand_tilde(&Word {
+        text: dir.to_string(),
+        quoted: word.quoted,
+    }));
+
+    let Ok(entries) = std::fs::read_dir(search_dir) else {
+        return vec![];
+    };
+
+    let mut completions: Vec<Pair> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            // Hidden files are only offered once their `.` has been typed
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+
+            let is_dir = entry.path().is_dir();
+            let suffix = if is_dir { "/" } else { "" };
+            let path = format!("{dir}{name}{suffix}");
+            let replacement = match word.open_quote {
+                Some(quote) => {
+                    let close = if is_dir { "" } else { &quote.to_string() };
+                    format!("{quote}{}{close}", escape_in_quote(&path, quote))
+                }
+                None => escape(&path),
+            };
+
+            Some(Pair {
+                display: format!("{name}{suffix}"),
+                replacement,
+            })
+        })
+        .collect();
+    completions.sort_by(|a, b| a.display.cmp(&b.display));
+    completions
+}
+
+/// Escape the characters in `text` that would otherwise have a special meaning
+/// unquoted.
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for ch in text.chars() {
+        if ch.is_whitespace() || "'\"\\|$`#*?[".contains(ch) {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+/// Escape the characters in `text` that would otherwise end or have a special
+/// meaning inside an open `quote`.
+fn escape_in_quote(text: &str, quote: char) -> String {
+    let mut escaped = String::new();
+    for ch in text.chars() {
+        match (quote, ch) {
+            // Close the quote, escape the `'` and open it again
+            ('\'', '\'') => escaped.push_str(r"'\''"),
+            ('"', '$' | '`' | '"' | '\\') => {
+                escaped.push('\\');
+                escaped.push(ch);
+            }
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Complete the partial value `word` with the values `command` offers for the
+/// argument it's for, if it offers any.
+fn complete_value(
+    command: Option<&dyn Command>,
+    parser: &clap::Command,
+    words: &VecDeque<String>,
+    word: &PartialWord,
+) -> Option<Vec<Pair>> {
+    let arg = value_arg(parser, words)?;
+    let values = command?.complete_arg(arg.get_id().as_str(), &word.text)?;
+    Some(quote_values(values, word))
+}
+
+/// Complete the partial value `word` according to the value hint of the
+/// argument it's for: as a path unless the hint says it's something else.
+fn complete_hinted(
+    parser: &clap::Command,
+    words: &VecDeque<String>,
+    word: &PartialWord,
+    current_dir: &Path,
+) -> Vec<Pair> {
+    let hint = value_arg(parser, words).map_or(ValueHint::Unknown, clap::Arg::get_value_hint);
+    match hint {
+        ValueHint::Unknown
+        | ValueHint::AnyPath
+        | ValueHint::FilePath
+        | ValueHint::ExecutablePath => complete_path(word, current_dir),
+        ValueHint::DirPath => complete_path(word, current_dir)
+            .into_iter()
+            .filter(|pair| pair.display.ends_with('/'))
+            .collect(),
+        ValueHint::Hostname => quote_values(known_hostnames(), word),
+        // Usernames, URLs, etc. can't be guessed
+        _ => vec![],
+    }
+}
+
+/// The `values` that complete the partial value `word`, each quoted the way
+/// `word` was, closing a quote that was left open.
+fn quote_values(values: Vec<String>, word: &PartialWord) -> Vec<Pair> {
+    values
+        .into_iter()
+        .filter(|value| value.starts_with(&word.text))
+        .map(|value| Pair {
+            replacement: match word.open_quote {
+                Some(quote) => format!("{quote}{}{quote} ", escape_in_quote(&value, quote)),
+                None => format!("{} ", escape(&value)),
+            },
+            display: value,
+        })
+        .collect()
+}
+
+/// The hostnames in the system's hosts file, sorted and without duplicates.
+fn known_hostnames() -> Vec<String> {
+    let path = if cfg!(windows) {
+        r"C:\Windows\System32\drivers\etc\hosts"
+    } else {
+        "/etc/hosts"
+    };
+    let Ok(hosts) = std::fs::read_to_string(path) else {
+        return vec![];
+    };
+
+    let mut names: Vec<String> = hosts
+        .lines()
+        .flat_map(|line| {
+            let entry = line.split('#').next().unwrap_or_default();
+            // Each entry is an address followed by its names
+            entry.split_whitespace().skip(1).map(str::to_string)
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// The argument of `parser` that the word after the complete `words` is a
+/// value of: the option just before it, if it takes a value, or else the next
+/// positional that can still take one.
+fn value_arg<'a>(parser: &'a clap::Command, words: &VecDeque<String>) -> Option<&'a clap::Arg> {
+    if let Some(option) = words.back().and_then(|flag| find_opt(parser, flag)) {
+        return option.get_action().takes_values().then_some(option);
+    }
+
+    let mut supplied = count_positionals(parser, words);
+    parser.get_positionals().find(|arg| {
+        let capacity = positional_capacity(arg);
+        if supplied >= capacity {
+            supplied -= capacity;
+            false
+        } else {
+            true
+        }
+    })
+}
+
+/// Whether `word` looks like a number, such as `-5` or `-0.5`.
+fn is_number(word: &str) -> bool {
+    word.parse::<f64>().is_ok_and(f64::is_finite)
+}
+
+/// Whether the word after `previous` is a value rather than a flag: either
+/// `previous` is an option that takes a value, or `parser` has positional
+/// arguments. A parser with short flags like `-1` only takes flags instead.
+fn expects_value(parser: &clap::Command, previous: Option<&String>) -> bool {
+    let has_digit_flags = parser
+        .get_arguments()
+        .any(|arg| arg.get_short().is_some_and(|c| c.is_ascii_digit()));
+    if has_digit_flags {
+        return false;
+    }
+
+    let previous_takes_value = previous.is_some_and(|previous| takes_value(parser, previous));
+    previous_takes_value || parser.get_positionals().next().is_some()
+}
+
+/// Whether `flag`, such as `--speed` or `-s`, is an option of `parser` that
+/// takes its value from the next word.
+fn takes_value(parser: &clap::Command, flag: &str) -> bool {
+    find_opt(parser, flag).is_some_and(|arg| arg.get_action().takes_values())
+}
+
+/// The option of `parser` named by `flag`, such as `--speed` or `-s`.
+fn find_opt<'a>(parser: &'a clap::Command, flag: &str) -> Option<&'a clap::Arg> {
+    parser.get_opts().find(|arg| match flag.strip_prefix("--") {
+        Some(long) => arg.get_long() == Some(long),
+        None => flag
+            .strip_prefix('-')
+            .and_then(|short| short.parse::<char>().ok())
+            .is_some_and(|short| arg.get_short() == Some(short)),
+    })
+}
+
+/// How many values the positional `arg` takes, or `usize::MAX` if it takes
+/// any number of them.
+fn positional_capacity(arg: &clap::Arg) -> usize {
+    if matches!(arg.get_action(), ArgAction::Append) {
+        return usize::MAX;
+    }
+    arg.get_num_args().map_or(1, |range| range.max_values())
+}
+
+/// How many of the complete `words` typed after a command's name are
+/// positional values, rather than flags or the values of options.
+fn count_positionals(parser: &clap::Command, words: &VecDeque<String>) -> usize {
+    let mut count = 0;
+    let mut after_separator = false;
+    let mut is_value = false;
+    for word in words {
+        if std::mem::take(&mut is_value) {
+            continue;
+        }
+
+        if after_separator
+            || !word.starts_with('-')
+            || word == "-"
+            || is_number(word) && expects_value(parser, None)
+        {
+            count += 1;
+        } else if word == "--" {
+            after_separator = true;
+        } else {
+            is_value = takes_value(parser, word);
+        }
+    }
+    count
+}
+
+/// The flags and options of `parser` given in `words`, as in `--speed=5` or
+/// `-ab`.
+fn used_args<'a, 'w>(
+    parser: &'a clap::Command,
+    words: impl Iterator<Item = &'w String>,
+) -> Vec<&'a clap::Arg> {
+    let mut used = vec![];
+    for word in words {
+        if let Some(long) = word.strip_prefix("--") {
+            let long = long.split('=').next().unwrap_or_default();
+            used.extend(parser.get_opts().find(|arg| arg.get_long() == Some(long)));
+        } else if let Some(shorts) = word.strip_prefix('-').filter(|_| !is_number(word)) {
+            for short in shorts.chars() {
+                let Some(arg) = parser.get_opts().find(|arg| arg.get_short() == Some(short)) else {
+                    break;
+                };
+                used.push(arg);
+                // The rest of the word is the option's value
+                if arg.get_action().takes_values() {
+                    break;
                 }
             }
         }
     }
+    used
+}
+
+/// The arguments of `parser` that can't be given alongside those already in
+/// `words`, because they share a group that allows only one of its members.
+fn conflicting_args<'a, 'w>(
+    parser: &'a clap::Command,
+    words: impl Iterator<Item = &'w String>,
+) -> HashSet<&'a clap::Id> {
+    let used: HashSet<&clap::Id> = used_args(parser, words)
+        .into_iter()
+        .map(clap::Arg::get_id)
+        .collect();
+
+    let mut conflicts = HashSet::new();
+    // `is_multiple` needs a mutable group, only because it's a builder
+    for group in parser
+        .get_groups()
+        .filter(|group| !clap::ArgGroup::clone(group).is_multiple())
+    {
+        let members: Vec<&clap::Id> = group.get_args().collect();
+        if members.iter().any(|id| used.contains(id)) {
+            conflicts.extend(members.into_iter().filter(|id| !used.contains(id)));
+        }
+    }
+    conflicts
+}
+
+/// Whether `arg` is a short flag that takes no value, and so can be combined
+/// with other such flags, as in `-abc`.
+fn is_bundleable(arg: &clap::Arg) -> bool {
+    arg.get_short().is_some() && !arg.is_positional() && !arg.get_action().takes_values()
+}
+
+/// If `word` is one boolean short flag or a bundle of them, such as `-a` or
+/// `-ab`, return the flags in it.
+fn bundled_shorts(parser: &clap::Command, word: &str) -> Option<Vec<char>> {
+    let shorts: Vec<char> = word.strip_prefix('-')?.chars().collect();
+    if shorts.is_empty() || shorts[0] == '-' {
+        return None;
+    }
+
+    shorts
+        .iter()
+        .all(|&c| {
+            parser
+                .get_arguments()
+                .any(|arg| arg.get_short() == Some(c) && is_bundleable(arg))
+        })
+        .then_some(shorts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A completer for `cmds`, under their own names.
+    fn completer_with(cmds: Vec<Box<dyn Command>>) -> CommandCompleter {
+        let commands: CommandSet = Default::default();
+        for cmd in cmds {
+            commands.borrow_mut().insert(cmd.get_name(), cmd);
+        }
+        CommandCompleter::new(commands)
+    }
+
+    fn complete(completer: &CommandCompleter, line: &str) -> (usize, Vec<String>) {
+        let history = rustyline::history::DefaultHistory::new();
+        let ctx = rustyline::Context::new(&history);
+        let (start, pairs) = completer.complete(line, line.len(), &ctx).unwrap();
+        (start, pairs.into_iter().map(|p| p.replacement).collect())
+    }
+
+    struct MoveCommand;
+
+    impl crate::console::Command for MoveCommand {
+        fn get_name(&self) -> String {
+            "move".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("move")
+                .arg(clap::Arg::new("distance").allow_negative_numbers(true))
+                .arg(clap::Arg::new("speed").short('s').long("speed"))
+                .arg(
+                    clap::Arg::new("gear")
+                        .long("gear")
+                        .value_parser(["low", "high", "highest"]),
+                )
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut crate::console::ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn negative_numbers_are_not_flags() {
+        let completer = completer_with(vec![Box::new(MoveCommand)]);
+
+        assert_eq!(complete(&completer, "move -5"), (7, vec![]));
+        assert_eq!(complete(&completer, "move -s -1.5"), (12, vec![]));
+        assert_eq!(
+            complete(&completer, "move -"),
+            (5, vec!["-s ".to_string(), "--gear ".to_string()])
+        );
+    }
+
+    #[test]
+    fn candidates_can_be_filtered() {
+        let filter: Rc<CompletionFilter> = Rc::new(|candidates: &mut Vec<Pair>| {
+            candidates.retain(|pair| pair.replacement != "--gear ");
+            candidates.push(Pair {
+                display: "extra".to_string(),
+                replacement: "extra".to_string(),
+            });
+        });
+        let completer = completer_with(vec![Box::new(MoveCommand)]).with_filter(Some(filter));
+
+        assert_eq!(
+            complete(&completer, "move -"),
+            (5, vec!["-s ".to_string(), "extra".to_string()])
+        );
+    }
+
+    #[test]
+    fn completes_values_of_long_options() {
+        let completer = completer_with(vec![Box::new(MoveCommand)]);
+
+        assert_eq!(
+            complete(&completer, "move --gear=hi"),
+            (
+                5,
+                vec!["--gear=high ".to_string(), "--gear=highest ".to_string()]
+            )
+        );
+        assert_eq!(
+            complete(&completer, "move --gear="),
+            (
+                5,
+                vec![
+                    "--gear=low ".to_string(),
+                    "--gear=high ".to_string(),
+                    "--gear=highest ".to_string()
+                ]
+            )
+        );
+        assert_eq!(complete(&completer, "move --speed=1"), (5, vec![]));
+    }
+
+    #[test]
+    fn notes_aliases_next_to_the_commands_they_rename() {
+        let aliases: AliasSet = Default::default();
+        for (alias, value) in [("m", "move"), ("mv", "move"), ("mf", "move -s 10")] {
+            aliases
+                .borrow_mut()
+                .insert(alias.to_string(), value.to_string());
+        }
+        let completer = completer_with(vec![Box::new(MoveCommand)]).with_aliases(aliases);
+        let history = rustyline::history::DefaultHistory::new();
+        let ctx = rustyline::Context::new(&history);
+
+        assert_eq!(
+            complete(&completer, "m"),
+            (0, vec!["mf".to_string(), "move".to_string()])
+        );
+        let (_, pairs) = completer.complete("mo", 2, &ctx).unwrap();
+        assert!(pairs[0].display.starts_with("move (") && pairs[0].display.contains("mv"));
+        // Without the command they rename, aliases are listed themselves
+        assert_eq!(complete(&completer, "mv"), (0, vec!["mv".to_string()]));
+    }
+
+    #[test]
+    fn completes_session_variables_after_a_dollar() {
+        let vars = Rc::new(RefCell::new(vec![
+            "name".to_string(),
+            "nap".to_string(),
+            "user".to_string(),
+        ]));
+        let completer = completer_with(vec![Box::new(MoveCommand)]).with_vars(vars);
+
+        assert_eq!(
+            complete(&completer, "move $na"),
+            (5, vec!["$name".to_string(), "$nap".to_string()])
+        );
+        assert_eq!(complete(&completer, "move $").1.len(), 3);
+        assert_eq!(complete(&completer, "move '$na"), (5, vec![]));
+    }
+
+    #[test]
+    fn handles_multibyte_characters() {
+        let completer = completer_with(vec![Box::new(MoveCommand)]).with_case_insensitive(true);
+        let history = rustyline::history::DefaultHistory::new();
+        let ctx = rustyline::Context::new(&history);
+
+        assert_eq!(complete(&completer, "MOVÉ"), (0, vec![]));
+        assert_eq!(
+            complete(&completer, "move café | mo"),
+            (13, vec!["move".to_string()])
+        );
+        assert_eq!(
+            complete(&completer, "move é --sp"),
+            (8, vec!["--speed".to_string()])
+        );
+        // The word starts where it was typed, not where its unescaped text would
+        assert_eq!(
+            complete(&completer, r"move é --s\p"),
+            (8, vec!["--speed".to_string()])
+        );
+
+        // The cursor is before a `|`, or inside a character
+        let (start, pairs) = completer.complete("mo | café", 2, &ctx).unwrap();
+        assert_eq!((start, pairs.len()), (0, 1));
+        let (_, pairs) = completer.complete("café", 4, &ctx).unwrap();
+        assert!(pairs.is_empty());
+    }
+
+    #[test]
+    fn completes_only_remaining_positionals() {
+        let completer = completer_with(vec![Box::new(MoveCommand)]);
+
+        assert_eq!(
+            complete(&completer, "move -s 3 "),
+            (10, vec!["".to_string()])
+        );
+        assert_eq!(complete(&completer, "move -5 "), (8, vec![]));
+        assert_eq!(complete(&completer, "move --gear=low 5 "), (18, vec![]));
+        assert_eq!(complete(&completer, "help move "), (10, vec![]));
+        // Variadic positionals can always take more
+        assert_eq!(
+            complete(&completer, "which a b "),
+            (10, vec!["".to_string()])
+        );
+    }
+
+    struct TurnCommand;
+
+    impl crate::console::Command for TurnCommand {
+        fn get_name(&self) -> String {
+            "turn".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            let flag = |name| clap::Arg::new(name).long(name).action(ArgAction::SetTrue);
+            clap::Command::new("turn")
+                .arg(flag("left").short('l'))
+                .arg(flag("right").short('r'))
+                .arg(flag("slowly"))
+                .group(clap::ArgGroup::new("direction").args(["left", "right"]))
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut crate::console::ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn leaves_out_conflicting_group_members() {
+        let completer = completer_with(vec![Box::new(TurnCommand)]);
+
+        assert_eq!(
+            complete(&completer, "turn --"),
+            (
+                5,
+                vec![
+                    "--left".to_string(),
+                    "--right".to_string(),
+                    "--slowly".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            complete(&completer, "turn --left --"),
+            (12, vec!["--left".to_string(), "--slowly".to_string()])
+        );
+        assert_eq!(
+            complete(&completer, "turn -r -"),
+            (8, vec!["-r".to_string(), "--slowly".to_string()])
+        );
+    }
+
+    struct PackCommand;
+
+    impl crate::console::Command for PackCommand {
+        fn get_name(&self) -> String {
+            "pack".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            let flag = |id: &'static str| {
+                clap::Arg::new(id)
+                    .short(id.chars().next())
+                    .action(ArgAction::SetTrue)
+            };
+            clap::Command::new("pack")
+                .arg(flag("a"))
+                .arg(flag("b").long("bzip"))
+                .arg(flag("c"))
+                .arg(clap::Arg::new("v").short('v').action(ArgAction::Count))
+                .arg(clap::Arg::new("o").short('o'))
+                .arg(clap::Arg::new("level").short('l').action(ArgAction::Append))
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut crate::console::ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn completes_bundles_of_short_flags() {
+        let completer = completer_with(vec![Box::new(PackCommand)]);
+        let bundles = |bundle: &str, shorts: &str| -> Vec<String> {
+            shorts.chars().map(|c| format!("{bundle}{c}")).collect()
+        };
+
+        assert_eq!(complete(&completer, "pack -a"), (5, bundles("-a", "bcv")));
+        // Flags already in the bundle are left out, unless they're counted
+        assert_eq!(complete(&completer, "pack -ab"), (5, bundles("-ab", "cv")));
+        assert_eq!(complete(&completer, "pack -av"), (5, bundles("-av", "bcv")));
+        // Shorts that take a value can't be bundled, or appended to a bundle
+        assert_eq!(complete(&completer, "pack -ao"), (5, vec![]));
+        assert_eq!(complete(&completer, "pack -al"), (5, vec![]));
+    }
+
+    struct RemoteCommand;
+
+    impl crate::console::Command for RemoteCommand {
+        fn get_name(&self) -> String {
+            "remote".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("remote")
+                .subcommand(
+                    clap::Command::new("add").visible_alias("new").arg(
+                        clap::Arg::new("force")
+                            .long("force")
+                            .action(ArgAction::SetTrue),
+                    ),
+                )
+                .subcommand(clap::Command::new("remove").visible_alias("rm"))
+                .subcommand(clap::Command::new("rename"))
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut crate::console::ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn completes_subcommands_and_their_aliases() {
+        let completer = completer_with(vec![Box::new(RemoteCommand)]);
+
+        assert_eq!(
+            complete(&completer, "remote "),
+            (
+                7,
+                vec!["add", "new", "remove", "rm", "rename"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            )
+        );
+        assert_eq!(
+            complete(&completer, "remote re"),
+            (7, vec!["remove".to_string(), "rename".to_string()])
+        );
+        assert_eq!(
+            complete(&completer, "remote n"),
+            (7, vec!["new".to_string()])
+        );
+        // The arguments of a subcommand are completed, whatever it's called
+        assert_eq!(
+            complete(&completer, "remote new --f"),
+            (11, vec!["--force".to_string()])
+        );
+    }
+
+    #[test]
+    fn ignores_surrounding_whitespace() {
+        let completer = completer_with(vec![Box::new(MoveCommand)]);
+
+        assert_eq!(complete(&completer, "  mo"), (2, vec!["move".to_string()]));
+        assert_eq!(complete(&completer, "\tmo"), (1, vec!["move".to_string()]));
+        assert_eq!(
+            complete(&completer, "  move -"),
+            (7, vec!["-s ".to_string(), "--gear ".to_string()])
+        );
+        assert_eq!(complete(&completer, "  move  "), (8, vec!["".to_string()]));
+        assert_eq!(
+            complete(&completer, "move |   mo"),
+            (9, vec!["move".to_string()])
+        );
+    }
+
+    /// Connect to one of the hosts known at the time.
+    struct ConnectCommand {
+        hosts: std::rc::Rc<RefCell<Vec<String>>>,
+    }
+
+    impl crate::console::Command for ConnectCommand {
+        fn get_name(&self) -> String {
+            "connect".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("connect")
+                .arg(clap::Arg::new("host"))
+                .arg(clap::Arg::new("user").short('u'))
+        }
+
+        fn complete_arg(&self, arg: &str, _prefix: &str) -> Option<Vec<String>> {
+            (arg == "host").then(|| self.hosts.borrow().clone())
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut crate::console::ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn completes_values_offered_by_commands() {
+        let hosts = std::rc::Rc::new(RefCell::new(vec!["alpha".to_string()]));
+        let completer = completer_with(vec![Box::new(ConnectCommand {
+            hosts: hosts.clone(),
+        })]);
+
+        assert_eq!(
+            complete(&completer, "connect "),
+            (8, vec!["alpha ".to_string()])
+        );
+        hosts.borrow_mut().push("my host".to_string());
+        assert_eq!(
+            complete(&completer, "connect -u me m"),
+            (14, vec![r"my\ host ".to_string()])
+        );
+        // Other arguments are still completed as paths
+        assert_eq!(
+            complete(&completer, "connect alpha -u src/li").1,
+            ["src/lib.rs"]
+        );
+    }
+
+    #[test]
+    fn completes_values_in_open_quotes() {
+        let hosts = vec!["my host".to_string(), "it's".to_string()];
+        let completer = completer_with(vec![Box::new(ConnectCommand {
+            hosts: std::rc::Rc::new(RefCell::new(hosts)),
+        })]);
+
+        assert_eq!(
+            complete(&completer, r#"connect "my h"#),
+            (8, vec![r#""my host" "#.to_string()])
+        );
+        assert_eq!(
+            complete(&completer, "connect 'my"),
+            (8, vec!["'my host' ".to_string()])
+        );
+        assert_eq!(
+            complete(&completer, r#"connect -u me "it"#),
+            (14, vec![r#""it's" "#.to_string()])
+        );
+        assert_eq!(
+            complete(&completer, "connect alpha -u 'src/li"),
+            (17, vec!["'src/lib.rs'".to_string()])
+        );
+    }
+
+    struct OpenCommand;
+
+    impl crate::console::Command for OpenCommand {
+        fn get_name(&self) -> String {
+            "open".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("open")
+                .arg(clap::Arg::new("dir").value_hint(ValueHint::DirPath))
+                .arg(
+                    clap::Arg::new("user")
+                        .short('u')
+                        .value_hint(ValueHint::Username),
+                )
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut crate::console::ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn completes_by_value_hint() {
+        let dir = crate::tests::TempDir::new("hints");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("dog.txt"), "").unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let completer = completer_with(vec![Box::new(OpenCommand)]);
+
+        let line = format!("open {dir}/do");
+        assert_eq!(
+            complete(&completer, &line),
+            (5, vec![format!("{dir}/docs/")])
+        );
+        assert_eq!(complete(&completer, "open -u ro"), (8, vec![]));
+    }
+
+    #[test]
+    fn completes_paths_in_open_quotes() {
+        let dir = crate::tests::TempDir::new("completion");
+        std::fs::create_dir_all(dir.join("my dir")).unwrap();
+        std::fs::write(dir.join("my file's.txt"), "").unwrap();
+        let dir = dir.to_str().unwrap();
+
+        let completer = completer_with(vec![]);
+        let line = format!("help \"{dir}/my f");
+        assert_eq!(
+            complete(&completer, &line),
+            (5, vec![format!("\"{dir}/my file's.txt\"")])
+        );
+
+        let line = format!("help '{dir}/my");
+        assert_eq!(
+            complete(&completer, &line),
+            (
+                5,
+                vec![
+                    format!("'{dir}/my dir/"),
+                    format!(r"'{dir}/my file'\''s.txt'")
+                ]
+            )
+        );
+
+        let line = format!("help {dir}/my\\ d");
+        assert_eq!(
+            complete(&completer, &line),
+            (5, vec![format!(r"{dir}/my\ dir/")])
+        );
+    }
 }
@@ -1,17 +1,32 @@
 use std::{
     cell::RefCell,
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     fmt::Write as _,
-    io::Write as _,
+    io::{IsTerminal as _, Read as _, Write as _},
+    ops::Range,
+    path::{Path, PathBuf},
     process::Stdio,
     rc::Rc,
+    time::{Duration, Instant},
 };
 
 use colored::Colorize;
-use rustyline::{error::ReadlineError, Completer, Helper, Highlighter, Hinter, Validator};
+use rustyline::{
+    config::Configurer as _, error::ReadlineError, hint::HistoryHinter, Helper, Highlighter,
+    Validator,
+};
 use thiserror::Error;
 
-use crate::completion::CommandCompleter;
+use crate::{
+    completion::CommandCompleter,
+    expand,
+    highlight::CommandHighlighter,
+    idle::{IdleCallback, IdleTimeout, Stdin},
+    interrupt::InterruptGuard,
+    intrinsic::{self, Intrinsic},
+    lexer,
+    streaming::{self, StreamingCommand},
+};
 
 #[non_exhaustive]
 #[derive(Error, Debug)]
@@ -24,20 +39,226 @@ pub enum ConsoleError {
     LexingError(String),
     #[error("Error: empty command")]
     EmptyCommandLineError,
+    #[error("No matches found for pattern: `{0}`")]
+    NoGlobMatchesError(String),
     #[error("Unrecognized command: `{0}`")]
     UnrecognizedCommand(String),
     #[error("Error executing command `{0}`: {1}")]
     CommandError(String, String),
-    #[error("Pipeline broken: {0}")]
-    BrokenPipeError(Box<ConsoleError>),
+    #[error("Error reading response file `{0}`: {1}")]
+    ResponseFileError(String, String),
+    #[error("History event not found: `{0}`")]
+    HistoryEventError(String),
+    #[error("Error reading script: {0}")]
+    ScriptReadError(String),
+    #[error("Pipeline broken at stage {0} (`{1}`): {2}")]
+    BrokenPipeError(usize, String, Box<ConsoleError>),
+    #[error("Pipeline has {0} stages, more than the maximum of {1}")]
+    PipelineTooLongError(usize, usize),
+    #[error("{0}")]
+    InvalidArgumentsError(String),
+    #[error("External command `{0}` not found")]
+    ExternalCommandNotFoundError(String),
+    #[error("Error starting external command `{0}`: {1}")]
+    ExternalSpawnError(String, String),
+    #[error("External commands are disabled: `!{0}`")]
+    ExternalCommandsDisabledError(String),
+    #[error("External command `{0}` is not allowed")]
+    ExternalCommandNotAllowedError(String),
+    #[error("Output of `{0}` is not valid UTF-8, and can't be passed on to the next command")]
+    NonUtf8PipeDataError(String),
+    #[error("Output of `{0}` exceeded the limit of {1} bytes")]
+    OutputLimitError(String, usize),
+    #[error("Command `{0}` is misconfigured: {1}")]
+    InvalidCommandError(String, String),
+    #[error("`{0}` was interrupted")]
+    InterruptedError(String),
+}
+
+impl PartialEq for ConsoleError {
+    /// Compares the variants and their payloads. Readline errors, which
+    /// aren't comparable themselves, are equal if their messages are.
+    fn eq(&self, other: &Self) -> bool {
+        use ConsoleError::*;
+
+        match (self, other) {
+            (ReadlineError(a), ReadlineError(b)) => a.to_string() == b.to_string(),
+            (StdoutWriteError, StdoutWriteError)
+            | (EmptyCommandLineError, EmptyCommandLineError) => true,
+            (LexingError(a), LexingError(b))
+            | (NoGlobMatchesError(a), NoGlobMatchesError(b))
+            | (UnrecognizedCommand(a), UnrecognizedCommand(b))
+            | (HistoryEventError(a), HistoryEventError(b))
+            | (ScriptReadError(a), ScriptReadError(b))
+            | (InvalidArgumentsError(a), InvalidArgumentsError(b))
+            | (ExternalCommandNotFoundError(a), ExternalCommandNotFoundError(b))
+            | (ExternalCommandsDisabledError(a), ExternalCommandsDisabledError(b))
+            | (ExternalCommandNotAllowedError(a), ExternalCommandNotAllowedError(b))
+            | (NonUtf8PipeDataError(a), NonUtf8PipeDataError(b))
+            | (InterruptedError(a), InterruptedError(b)) => a == b,
+            (CommandError(a1, a2), CommandError(b1, b2))
+            | (ResponseFileError(a1, a2), ResponseFileError(b1, b2))
+            | (ExternalSpawnError(a1, a2), ExternalSpawnError(b1, b2))
+            | (InvalidCommandError(a1, a2), InvalidCommandError(b1, b2)) => a1 == b1 && a2 == b2,
+            (BrokenPipeError(a1, a2, a3), BrokenPipeError(b1, b2, b3)) => {
+                a1 == b1 && a2 == b2 && a3 == b3
+            }
+            (PipelineTooLongError(a1, a2), PipelineTooLongError(b1, b2)) => a1 == b1 && a2 == b2,
+            (OutputLimitError(a1, a2), OutputLimitError(b1, b2)) => a1 == b1 && a2 == b2,
+            _ => false,
+        }
+    }
+}
+
+/// A signal a command can send to the console by returning it as its error,
+/// e.g. `Err(Box::new(Control::StopPipeline))`. It's handled by the console
+/// and not reported as an error.
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Control {
+    /// Stop the pipeline successfully: the rest of its stages don't run, and
+    /// nothing is printed, including this stage's output. This differs from
+    /// returning `Ok` with no output, after which the next stage still runs
+    /// with empty stdin and may produce output of its own.
+    StopPipeline,
+    /// Stop the pipeline like [`Control::StopPipeline`], then enter a nested
+    /// console with its own prompt and commands until it is left with
+    /// [`Control::ExitSubConsole`].
+    EnterSubConsole(SubConsole),
+    /// Stop the pipeline like [`Control::StopPipeline`], then leave the
+    /// current sub-console, going back to the console it was entered from. At
+    /// the top level, this ends the interactive loop or script instead. This
+    /// is what the built-in `exit` does.
+    ExitSubConsole,
+}
+
+impl std::fmt::Display for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Control::StopPipeline => write!(f, "pipeline stopped"),
+            Control::EnterSubConsole(sub) => write!(f, "entering sub-console {:?}", sub.prompt),
+            Control::ExitSubConsole => write!(f, "exiting sub-console"),
+        }
+    }
+}
+
+impl std::error::Error for Control {}
+
+/// A nested console, entered with [`Control::EnterSubConsole`], such as a
+/// configuration mode. While it's active, its commands replace those of the
+/// console it was entered from, and its prompt is shown instead. The
+/// console's built-in commands and aliases are still available.
+pub struct SubConsole {
+    prompt: String,
+    commands: Vec<Box<dyn Command>>,
+}
+
+impl SubConsole {
+    pub fn new(prompt: impl Into<String>) -> Self {
+        Self {
+            prompt: prompt.into(),
+            commands: vec![],
+        }
+    }
+
+    pub fn add_command(mut self, cmd: Box<dyn Command>) -> Self {
+        self.commands.push(cmd);
+        self
+    }
+
+    /// Add each of `cmds` in turn, like [`SubConsole::add_command`].
+    pub fn add_commands(mut self, cmds: impl IntoIterator<Item = Box<dyn Command>>) -> Self {
+        self.commands.extend(cmds);
+        self
+    }
+}
+
+impl std::fmt::Debug for SubConsole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = self.commands.iter().map(|c| c.get_name()).collect();
+        f.debug_struct("SubConsole")
+            .field("prompt", &self.prompt)
+            .field("commands", &names)
+            .finish()
+    }
 }
 
-pub(crate) type CommandSet = Rc<RefCell<HashMap<String, Box<dyn Command>>>>;
+pub(crate) type CommandMap = HashMap<String, Box<dyn Command>>;
+
+pub(crate) type CommandSet = Rc<RefCell<CommandMap>>;
+
+/// Aliases defined with the `alias` intrinsic, by name.
+pub(crate) type AliasMap = BTreeMap<String, String>;
+
+pub(crate) type AliasSet = Rc<RefCell<AliasMap>>;
+
+/// The line editor the console reads lines with. See
+/// [`Console::cmd_loop_with_editor`].
+pub type ConsoleEditor = rustyline::Editor<ConsoleHelper, rustyline::history::DefaultHistory>;
+
+type CommandNotFoundHook = dyn FnMut(&str) -> bool;
+
+type LineRewriter = dyn FnMut(String) -> String;
+
+type ExitHook = dyn FnOnce();
+
+type OutputSink = dyn FnMut(&str);
+
+type OutputTransform = dyn FnMut(String) -> String;
+
+type ConfirmHandler = dyn Fn(&str) -> bool;
+
+pub(crate) type CompletionFilter = dyn Fn(&mut Vec<rustyline::completion::Pair>);
+
+/// The device a line's output can be redirected to, as in `cmd > /dev/null`,
+/// to discard it.
+const NULL_DEVICE: &str = if cfg!(windows) { "NUL" } else { "/dev/null" };
+
+/// The commands built into `cmd.exe` on Windows, which are run with `cmd /C`
+/// when called as external commands.
+const CMD_BUILTINS: &[&str] = &[
+    "assoc", "call", "cd", "chdir", "cls", "color", "copy", "date", "del", "dir", "echo", "erase",
+    "ftype", "md", "mkdir", "mklink", "move", "path", "popd", "pushd", "rd", "ren", "rename",
+    "rmdir", "set", "start", "time", "title", "type", "ver", "vol",
+];
+
+/// How often an external command is checked for having been interrupted.
+const INTERRUPT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How deeply scripts run by the built-in `source` can be nested, which stops
+/// a script that sources itself.
+const MAX_SOURCE_DEPTH: usize = 64;
 
-#[derive(Helper, Completer, Validator, Hinter, Highlighter)]
-struct ConsoleHelper {
-    #[rustyline(Completer)]
+/// Completes, highlights and hints the lines typed into a [`ConsoleEditor`].
+/// It's attached by the console, so is only needed to name the editor's type.
+#[derive(Helper, Validator, Highlighter)]
+pub struct ConsoleHelper {
     completer: CommandCompleter,
+    #[rustyline(Highlighter)]
+    highlighter: CommandHighlighter,
+    history_hinter: Option<HistoryHinter>,
+}
+
+// Derived, the candidate type would name the crate-private completer
+impl rustyline::completion::Completer for ConsoleHelper {
+    type Candidate = rustyline::completion::Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Self::Candidate>)> {
+        self.completer.complete(line, pos, ctx)
+    }
+}
+
+impl rustyline::hint::Hinter for ConsoleHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, ctx: &rustyline::Context<'_>) -> Option<String> {
+        self.history_hinter.as_ref()?.hint(line, pos, ctx)
+    }
 }
 
 pub trait Command {
@@ -48,260 +269,2617 @@ pub trait Command {
     // `clap::Command`
     fn get_parser(&self) -> clap::Command;
 
+    /// The category this command is listed under by the built-in `help`.
+    /// Commands without one are listed under "General".
+    fn category(&self) -> Option<String> {
+        None
+    }
+
+    /// A short, one-line usage hint printed after the parser's error when the
+    /// command's arguments can't be parsed, e.g. `"usage: greet <name>"`.
+    fn usage_hint(&self) -> Option<String> {
+        None
+    }
+
+    /// Extended documentation, such as examples or notes, printed by `help
+    /// <command>` after the parser's help.
+    fn long_help(&self) -> Option<String> {
+        None
+    }
+
+    /// The values the argument with the id `arg` could be completed to from
+    /// `prefix`, e.g. the hostnames in a config that changes while the console
+    /// runs. It's called on each tab, so state shared with the command, e.g.
+    /// through an `Rc<RefCell<_>>`, is always up to date. Values that don't
+    /// start with `prefix` are left out. Returning `None`, the default,
+    /// completes the argument as a path.
+    fn complete_arg(&self, _arg: &str, _prefix: &str) -> Option<Vec<String>> {
+        None
+    }
+
+    /// Run the command. `ctx.stdin` is the output of the previous stage of
+    /// the pipeline, if any, and anything written to `ctx.stdout` is passed on
+    /// to the next stage or printed at the end of the pipeline. See
+    /// [`ExecContext`] for what else a command is run with.
+    ///
+    /// Implementations written when `execute` took `stdin`, `stdout` and
+    /// `stderr` parameters can take `ctx: &mut ExecContext<'_>` instead and
+    /// start with `let ExecContext { stdin, stdout, stderr, .. } = ctx;`.
     // A generic `ArgMatches` is the best we can do, so it's up to the
     // implementor to convert `args` to their desired type.
     fn execute(
         &self,
         args: clap::ArgMatches,
-        stdin: &str,
-        stdout: &mut dyn std::fmt::Write,
+        ctx: &mut ExecContext<'_>,
     ) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Whether the command takes its arguments as they were typed, without
+    /// parsing them with its parser, e.g. for a calculator that takes an
+    /// arbitrary expression. Such a command is run with
+    /// [`Command::execute_raw`] instead of [`Command::execute`], and its
+    /// arguments aren't completed.
+    fn wants_raw_args(&self) -> bool {
+        false
+    }
+
+    /// Run a command that [wants raw arguments](Command::wants_raw_args).
+    /// `args` are the words after the command's name, with their quotes
+    /// removed but otherwise not expanded, so e.g. `*` isn't globbed. The
+    /// parser is still used for the command's help.
+    fn execute_raw(
+        &self,
+        _args: &[String],
+        _ctx: &mut ExecContext<'_>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Err("this command doesn't take raw arguments".into())
+    }
+
+    /// The asynchronous implementation of this command, for commands
+    /// registered with [`AsyncConsole::add_async_command`].
+    ///
+    /// [`AsyncConsole::add_async_command`]: crate::async_console::AsyncConsole::add_async_command
+    #[cfg(feature = "async")]
+    #[doc(hidden)]
+    fn as_async(&self) -> Option<&dyn crate::async_console::AsyncCommand> {
+        None
+    }
+
+    /// Whether lines run from the prompt that run this command, whether by
+    /// name, through an alias, in a pipeline or substitution or with `repeat`,
+    /// are kept out of the history, and so the history file, e.g. for a
+    /// `login` that takes a password as an argument. Defaults to `false`.
+    fn secret(&self) -> bool {
+        false
+    }
+
+    /// The streaming implementation of this command, which runs on its own
+    /// thread when the console has streaming enabled. See
+    /// [`crate::streaming`].
+    fn as_streaming(&self) -> Option<&dyn StreamingCommand> {
+        None
+    }
+
+    /// Whether the command's `stdout` goes straight to the terminal, or the
+    /// [output sink](Console::with_output_sink), as it's written, when the
+    /// command is the last stage of a line run with [`Console::run_line`], or
+    /// an async console's `run_line`, so that a long-running command shows its
+    /// progress. By default, and
+    /// whenever the output is piped to another stage or captured by `$(...)`,
+    /// it's buffered until the command returns. The output filter isn't
+    /// applied to output written straight to the terminal.
+    fn live_output(&self) -> bool {
+        false
+    }
+}
+
+/// What a command is run with by [`Command::execute`].
+pub struct ExecContext<'a> {
+    /// The output of the previous stage of the pipeline, if any.
+    pub stdin: &'a str,
+    /// Passed on to the next stage, or printed at the end of the pipeline.
+    pub stdout: &'a mut dyn std::fmt::Write,
+    /// For warnings and other diagnostics. It goes straight to the console's
+    /// stderr and never enters the pipeline, just like the stderr of an
+    /// external command.
+    pub stderr: &'a mut dyn std::fmt::Write,
+    /// The directory external commands run in, changed with the built-in
+    /// `cd`. See [`Console::current_dir`].
+    pub current_dir: &'a Path,
+    /// The session variables, which every command the console runs can read
+    /// and change. See [`Console::set_var`].
+    pub vars: &'a mut BTreeMap<String, String>,
+    confirm_handler: Option<&'a ConfirmHandler>,
+    interactive: bool,
+}
+
+impl<'a> ExecContext<'a> {
+    /// The lines of `stdin`, without their line endings. They borrow only the
+    /// input, so e.g. a filter can write to `stdout` as it goes through them.
+    pub fn stdin_lines(&self) -> std::str::Lines<'a> {
+        self.stdin.lines()
+    }
+
+    /// The same context, but writing to `stdout` instead, e.g. for a command
+    /// that wraps another and transforms its output.
+    pub fn with_stdout<'b>(&'b mut self, stdout: &'b mut dyn std::fmt::Write) -> ExecContext<'b> {
+        ExecContext {
+            stdin: self.stdin,
+            stdout,
+            stderr: &mut *self.stderr,
+            current_dir: self.current_dir,
+            vars: &mut *self.vars,
+            confirm_handler: self.confirm_handler,
+            interactive: self.interactive,
+        }
+    }
+
+    /// Ask the user a yes-or-no question, e.g. before deleting something,
+    /// returning whether they answered yes.
+    ///
+    /// In [`Console::cmd_loop`], `message` is printed to stderr followed by
+    /// `[y/N]`, and the answer is read from the terminal. Otherwise, e.g. in
+    /// [`Console::run_script`] run on its own, there's nobody to ask, so the
+    /// answer is no. Either way, [`Console::with_confirm_handler`] can answer
+    /// instead.
+    pub fn confirm(&self, message: &str) -> bool {
+        match self.confirm_handler {
+            Some(handler) => handler(message),
+            None if self.interactive => confirm_on_terminal(message),
+            None => false,
+        }
+    }
+}
+
+/// Ask the user to confirm `message` on the terminal. Anything but `y` or
+/// `yes`, ignoring case, is a no.
+fn confirm_on_terminal(message: &str) -> bool {
+    eprint!("{message} [y/N] ");
+    let _ = std::io::stderr().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    let answer = answer.trim();
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
 }
 
-enum Runnable<'a> {
+/// Information about one of a command's arguments, as declared by its parser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgInfo {
+    pub name: String,
+    pub short: Option<char>,
+    pub long: Option<String>,
+    pub help: Option<String>,
+    pub required: bool,
+}
+
+impl From<&clap::Arg> for ArgInfo {
+    fn from(arg: &clap::Arg) -> Self {
+        Self {
+            name: arg.get_id().to_string(),
+            short: arg.get_short(),
+            long: arg.get_long().map(str::to_string),
+            help: arg.get_help().map(|h| h.to_string()),
+            required: arg.is_required_set(),
+        }
+    }
+}
+
+/// The commands registered with a console, borrowed with
+/// [`Console::commands`].
+pub struct Commands<'a>(std::cell::Ref<'a, CommandMap>);
+
+impl Commands<'_> {
+    /// Each command and its name, in order of name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &dyn Command)> {
+        let mut commands: Vec<_> = self
+            .0
+            .iter()
+            .map(|(name, cmd)| (name.as_str(), cmd.as_ref()))
+            .collect();
+        commands.sort_by_key(|(name, _)| *name);
+        commands.into_iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Command> {
+        self.0.get(name).map(|cmd| cmd.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+pub(crate) enum Runnable<'a> {
     External {
         name: String,
         args: Vec<String>,
+        /// Whether the output is passed on to another stage, and so has to be
+        /// valid UTF-8.
+        piped: bool,
+        /// Whether stderr is redirected into the output with `2>&1`.
+        merge_stderr: bool,
     },
     Command {
         cmd: &'a dyn Command,
         args: clap::ArgMatches,
     },
+    /// A command that takes its arguments unparsed.
+    Raw {
+        cmd: &'a dyn Command,
+        args: Vec<String>,
+    },
+    Intrinsic {
+        intrinsic: Intrinsic,
+        args: clap::ArgMatches,
+    },
+    /// The help or version text a command's parser rendered instead of
+    /// parsing its arguments, which becomes that stage's output.
+    Text { name: String, text: String },
 }
 
-pub struct Console {
-    prompt: String,
-    commands: CommandSet,
-}
-
-fn split_pipeline(pipeline: &str) -> Vec<&str> {
-    enum Quote {
-        Single,
-        Double,
-    }
-
-    let mut quote = None;
-    let mut command_lines = vec![];
-    let mut last_end_idx = 0;
-    for (idx, ch) in pipeline.char_indices() {
-        match ch {
-            '\'' => {
-                quote = match quote {
-                    Some(kind) => match kind {
-                        Quote::Single => None,
-                        Quote::Double => Some(Quote::Single),
-                    },
-                    None => Some(Quote::Single),
+impl Runnable<'_> {
+    /// A human-readable description of what running this stage would do.
+    fn describe(&self) -> String {
+        match self {
+            Runnable::External {
+                name,
+                args,
+                merge_stderr,
+                ..
+            } => {
+                let merged = if *merge_stderr {
+                    ", with stderr in its output"
+                } else {
+                    ""
                 };
+                format!("external command `{name}` with arguments {args:?}{merged}")
             }
-            '"' => {
-                quote = match quote {
-                    Some(kind) => match kind {
-                        Quote::Single => Some(Quote::Double),
-                        Quote::Double => None,
-                    },
-                    None => Some(Quote::Double),
-                };
+            Runnable::Command { cmd, args } => {
+                let args = describe_args(&cmd.get_parser(), args);
+                format!("command `{}`{args}", cmd.get_name())
             }
-            '|' => {
-                if quote.is_none() {
-                    command_lines.push(&pipeline[last_end_idx..idx]);
-                    last_end_idx = idx + 1
-                }
+            Runnable::Raw { cmd, args } => {
+                format!("command `{}` with raw arguments {args:?}", cmd.get_name())
             }
-            _ => (),
+            Runnable::Intrinsic { intrinsic, args } => {
+                let args = describe_args(&intrinsic.parser(), args);
+                format!("built-in command `{}`{args}", intrinsic.name())
+            }
+            Runnable::Text { name, .. } => format!("help or version text of `{name}`"),
         }
     }
-    // Last one
-    command_lines.push(&pipeline[last_end_idx..]);
-
-    command_lines
 }
 
-impl Console {
-    pub fn cmd_loop(&mut self) -> Result<(), ConsoleError> {
-        let rl_config = rustyline::Config::builder()
-            .check_cursor_position(true) // Prevent overwriting of stdout
-            .auto_add_history(true)
-            .completion_type(rustyline::CompletionType::List)
-            .build();
-        let mut rl = rustyline::Editor::with_config(rl_config)?;
-        rl.set_helper(Some(ConsoleHelper {
-            completer: CommandCompleter::new(Rc::clone(&self.commands)),
-        }));
+/// Describe the arguments given on the command line in `args`, which were
+/// parsed by `parser`, as e.g. ` with name=["value"]`, or nothing if there
+/// are none.
+fn describe_args(parser: &clap::Command, args: &clap::ArgMatches) -> String {
+    let given: Vec<String> = parser
+        .get_arguments()
+        .map(|arg| arg.get_id().as_str())
+        .filter(|&id| args.value_source(id) == Some(clap::parser::ValueSource::CommandLine))
+        .map(|id| {
+            let values: Vec<_> = args
+                .get_raw(id)
+                .into_iter()
+                .flatten()
+                .map(|v| v.to_string_lossy())
+                .collect();
+            format!("{id}={values:?}")
+        })
+        .collect();
 
-        'command_loop: loop {
-            let readline = match rl.readline(&self.prompt) {
-                Ok(o) => o,
-                Err(e) => match e {
-                    ReadlineError::Eof => return Ok(()),
-                    _ => return Err(ConsoleError::from(e)),
-                },
-            };
+    if given.is_empty() {
+        String::new()
+    } else {
+        format!(" with {}", given.join(", "))
+    }
+}
 
-            // This needs to be borrowed here. self.commands shall not mutate
-            // for the rest of this iteration of command_loop.
-            let command_set = &self.commands.borrow();
+/// The result of parsing a command line.
+pub(crate) enum Parsed<'a> {
+    /// The stages of the pipeline, ready to run.
+    Pipeline(VecDeque<Runnable<'a>>),
+    /// Nothing needs to run, and the line finished with this exit status.
+    Finished(i32),
+}
 
-            let command_lines = split_pipeline(&readline);
-            let mut runnables: VecDeque<Runnable> = VecDeque::new();
+/// If `line` ends by redirecting its output to the null device, as in `cmd >
+/// /dev/null` or `cmd >NUL` on Windows, return the line without the
+/// redirection. Backslashes are lexed as `backslash` says.
+pub(crate) fn strip_null_redirect(line: &str, backslash: lexer::Backslash) -> Option<&str> {
+    // The redirection mustn't be quoted or escaped, as in `echo '> /dev/null'`
+    let words = lexer::split_with(line, backslash)?;
+    let redirects = match words.as_slice() {
+        [.., redirect] if !redirect.quoted && redirect.text == format!(">{NULL_DEVICE}") => true,
+        [.., redirect, device] => {
+            !redirect.quoted && !device.quoted && redirect.text == ">" && device.text == NULL_DEVICE
+        }
+        _ => false,
+    };
+    if !redirects {
+        return None;
+    }
 
-            /*
-             * First, parse every command in the pipeline. If one fails, then
-             * the pipeline shouldn't run at all.
-             */
-            for command_line in command_lines {
-                let tokens = shlex::split(command_line)
-                    .ok_or_else(|| ConsoleError::LexingError(command_line.to_string()))?;
+    let rest = line
+        .trim_end_matches(lexer::is_blank)
+        .strip_suffix(NULL_DEVICE)?;
+    rest.trim_end_matches(lexer::is_blank).strip_suffix('>')
+}
 
-                if tokens.is_empty() {
-                    eprintln!("{}", ConsoleError::EmptyCommandLineError);
-                    continue 'command_loop;
-                }
+/// Adapts the console process's stderr for use as a command's `stderr` sink.
+pub(crate) struct StderrWriter;
 
-                // Handle possible external commands, prefixed by !
-                let (external_cmd, rest) = if tokens[0] == "!" {
-                    // Standalone '!'
-                    (tokens.get(1).map(|s| s.as_str()), &tokens[2..])
-                } else if tokens[0].chars().nth(0).is_some_and(|c| c == '!') {
-                    // Command starts with '!'
-                    (tokens.first().map(|s| &s[1..]), &tokens[1..])
-                } else {
-                    // No '!'
-                    (None, &[] as &[String])
-                };
+impl std::fmt::Write for StderrWriter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        std::io::stderr()
+            .write_all(s.as_bytes())
+            .map_err(|_| std::fmt::Error)
+    }
+}
 
-                if let Some(program) = external_cmd {
-                    runnables.push_back(Runnable::External {
-                        name: program.to_string(),
-                        args: rest.to_vec(),
-                    });
-                } else if let Some(cmd) = command_set.get(&tokens[0]) {
-                    let matches = match cmd.get_parser().try_get_matches_from(&tokens) {
-                        Ok(matches) => matches,
-                        Err(e) => {
-                            eprintln!("{e}");
-                            continue 'command_loop;
-                        }
-                    };
+/// The `stdout` of an internal stage, which keeps at most `max` bytes of its
+/// output and fails to write any more.
+struct LimitedWriter<'a> {
+    output: &'a mut String,
+    max: Option<usize>,
+    exceeded: bool,
+}
 
-                    runnables.push_back(Runnable::Command {
-                        cmd: cmd.as_ref(),
-                        args: matches,
-                    });
-                } else {
-                    eprintln!("{}", ConsoleError::UnrecognizedCommand(tokens[0].clone()));
-                    continue 'command_loop;
+impl std::fmt::Write for LimitedWriter<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        match self.max {
+            Some(max) if self.output.len() + s.len() > max => {
+                let mut end = max - self.output.len();
+                while !s.is_char_boundary(end) {
+                    end -= 1;
                 }
+                self.output.push_str(&s[..end]);
+                self.exceeded = true;
+                Err(std::fmt::Error)
             }
+            _ => {
+                self.output.push_str(s);
+                Ok(())
+            }
+        }
+    }
+}
 
-            let in_pipeline = runnables.len() > 1;
-
-            /*
-             * Now that we know each command exists and has appropriate
-             * arguments, run them in series and pass the output from each to
-             * the next.
-             */
-            let mut previous_output = String::new();
-            while let Some(runnable) = runnables.pop_front() {
-                let mut output_buf = String::new();
-                let (res, command_name) = match runnable {
-                    Runnable::External { name, args } => (
-                        Self::run_external_command(
-                            &name,
-                            &args.iter().map(|s| s.as_str()).collect(),
-                            &previous_output,
-                            &mut output_buf,
-                        ),
-                        name,
-                    ),
-                    Runnable::Command { cmd, args } => (
-                        cmd.execute(args, &previous_output, &mut output_buf),
-                        cmd.get_name(),
-                    ),
-                };
+/// Pass `output` to the console's output sink, if it has one, or print it to
+/// stdout and flush.
+fn write_output(sink: Option<&mut OutputSink>, output: &str) -> Result<(), ConsoleError> {
+    match sink {
+        Some(sink) => {
+            sink(output);
+            Ok(())
+        }
+        None => {
+            print!("{output}");
+            std::io::stdout()
+                .flush()
+                .map_err(|_| ConsoleError::StdoutWriteError)
+        }
+    }
+}
 
-                if let Err(error_msg) = res {
-                    let mut error = ConsoleError::CommandError(command_name, error_msg.to_string());
+/// The `stdout` sink of a command with [`Command::live_output`], which passes
+/// each write on to the console's output sink or stdout straight away.
+struct LiveWriter<'a>(Option<&'a mut OutputSink>);
 
-                    // If this is a pipeline of multiple commands, then wrap
-                    // the current command's error in a pipeline error.
-                    if in_pipeline {
-                        error = ConsoleError::BrokenPipeError(Box::new(error));
-                    }
+impl std::fmt::Write for LiveWriter<'_> {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        write_output(self.0.as_deref_mut(), s).map_err(|_| std::fmt::Error)
+    }
+}
 
-                    eprintln!("{}", error.to_string().red());
-                    continue 'command_loop;
-                }
+/// Settings for a console, all applied at once by [`Console::from_config`].
+/// Unset fields keep their defaults, e.g.
+/// `ConsoleConfig { prompt: "$ ".to_string(), ..Default::default() }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsoleConfig {
+    /// See [`Console::with_prompt`].
+    pub prompt: String,
+    /// See [`Console::with_history_file`].
+    pub history_file: Option<PathBuf>,
+    /// See [`Console::with_color`].
+    pub color: Option<bool>,
+    /// See [`Console::with_edit_mode`].
+    pub edit_mode: rustyline::EditMode,
+    /// See [`Console::with_externals`].
+    pub externals: bool,
+}
 
-                std::mem::swap(&mut previous_output, &mut output_buf);
-            }
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        Self {
+            prompt: "> ".to_string(),
+            history_file: None,
+            color: None,
+            edit_mode: rustyline::EditMode::Emacs,
+            externals: true,
+        }
+    }
+}
 
-            /*
-             * Print the output at the end of the pipeline
-             */
-            print!("{previous_output}");
-            std::io::stdout()
-                .flush()
-                .map_err(|_| ConsoleError::StdoutWriteError)?;
+pub struct Console {
+    prompt: String,
+    continuation_prompt: String,
+    status_prompt: bool,
+    comment_char: Option<char>,
+    commands: CommandSet,
+    output_filter: Option<Box<dyn Fn(String) -> String>>,
+    output_sink: Option<Box<OutputSink>>,
+    final_output_transform: Option<Box<OutputTransform>>,
+    confirm_handler: Option<Box<ConfirmHandler>>,
+    /// Whether lines are being read from the user by `cmd_loop`.
+    interactive: bool,
+    globbing: bool,
+    failglob: bool,
+    command_not_found: Option<Box<CommandNotFoundHook>>,
+    suggestion_threshold: usize,
+    last_status: i32,
+    response_files: bool,
+    aliases: AliasSet,
+    max_pipeline_len: Option<usize>,
+    max_output_bytes: Option<usize>,
+    timing: bool,
+    trace: bool,
+    windows_paths: bool,
+    history_file: Option<PathBuf>,
+    case_insensitive: bool,
+    completion_help: bool,
+    line_rewriter: Option<Box<LineRewriter>>,
+    completion_type: rustyline::CompletionType,
+    add_history: bool,
+    edit_mode: rustyline::EditMode,
+    on_exit: Option<Box<ExitHook>>,
+    idle_timeout: Option<IdleTimeout>,
+    /// The prompts and commands of the consoles that the current sub-console
+    /// was entered from, innermost last.
+    outer_consoles: Vec<(String, CommandMap)>,
+    pending_control: Option<Control>,
+    exit_requested: bool,
+    streaming: bool,
+    highlighting: bool,
+    history_hints: bool,
+    color: Option<bool>,
+    externals: bool,
+    external_allowlist: Option<HashSet<String>>,
+    completion_filter: Option<Rc<CompletionFilter>>,
+    /// The directory external commands run in, changed with `cd`.
+    current_dir: PathBuf,
+    /// A copy of `current_dir` shared with the completer.
+    completion_dir: Rc<RefCell<PathBuf>>,
+    previous_dir: Option<PathBuf>,
+    vars: BTreeMap<String, String>,
+    /// The names of `vars`, shared with the completer and updated before
+    /// each prompt.
+    completion_vars: Rc<RefCell<Vec<String>>>,
+    ansi_c_quoting: bool,
+    /// How many times each command has run, by name.
+    command_stats: HashMap<String, u64>,
+    /// How many scripts run by `source` are running inside each other.
+    source_depth: usize,
+    /// Whether a `repeat` is running, which can't run another.
+    repeating: bool,
+    startup_commands: Vec<String>,
+    abort_on_startup_error: bool,
+}
+
+/// Returns whether `line` ends in a backslash that is not itself escaped,
+/// meaning the logical line continues on the next physical line.
+fn has_line_continuation(line: &str) -> bool {
+    let trailing_backslashes = line.chars().rev().take_while(|&c| c == '\\').count();
+    trailing_backslashes % 2 == 1
+}
+
+/// If the logical line read so far continues on the next physical line,
+/// because it ends in an unescaped backslash or inside an open quote, prepare
+/// it to have the next line appended and return `true`. The backslash is
+/// removed, while an open quote gets the newline it contains.
+fn prepare_continuation(line: &mut String, backslash: lexer::Backslash) -> bool {
+    if lexer::ends_in_open_quote(line, backslash) {
+        line.push('\n');
+        true
+    } else if backslash == lexer::Backslash::Escape && has_line_continuation(line) {
+        line.pop();
+        true
+    } else {
+        false
+    }
+}
+
+/// Decide whether to emit ANSI color codes. `NO_COLOR` disables color,
+/// `CLICOLOR_FORCE` enables it even when not writing to a terminal and
+/// `CLICOLOR=0` disables it. Otherwise, color is used if stdout is a terminal.
+fn color_from_env() -> bool {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+
+    if var("NO_COLOR").is_some() {
+        false
+    } else if var("CLICOLOR_FORCE").is_some_and(|v| v != "0") {
+        true
+    } else if var("CLICOLOR").is_some_and(|v| v == "0") {
+        false
+    } else {
+        std::io::stdout().is_terminal()
+    }
+}
+
+/// The number of single-character insertions, deletions and substitutions
+/// needed to turn `a` into `b`.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
         }
     }
 
-    fn run_external_command(
-        name: &str,
-        args: &Vec<&str>,
-        stdin: &str,
-        stdout: &mut String,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        /*
-         * There are a lot of `expect()`s here. Maybe at some point these can be
-         * handled, but for now they are outside the scope of an
-         * user-interactive application.
-         */
+    row[b.len()]
+}
 
-        let mut child = std::process::Command::new(name)
-            .args(args)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            // .map_err(|e| e.to_string())?;
-            .map_err(|e| e.to_string())?;
+/// If `line` is a history event designator, return the history entry it
+/// refers to: `!!` for the previous line or `!n` for the `n`th line, counting
+/// from 1. Any other line, including external commands like `!ls`, gives
+/// `None`.
+fn expand_history_event(
+    line: &str,
+    history: &dyn rustyline::history::History,
+) -> Result<Option<String>, ConsoleError> {
+    let event = line.trim();
+    let index = if event == "!!" {
+        history.len().checked_sub(1)
+    } else if let Some(n) = event
+        .strip_prefix('!')
+        .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    {
+        n.parse::<usize>().ok().and_then(|n| n.checked_sub(1))
+    } else {
+        return Ok(None);
+    };
 
-        let mut child_stdin = child
-            .stdin
-            .take()
-            .expect("Could not acquire stdin for child process");
+    let entry = index
+        .map(|i| history.get(i, rustyline::history::SearchDirection::Forward))
+        .transpose()?
+        .flatten();
 
-        std::thread::scope(|s| {
-            s.spawn(move || child_stdin.write_all(stdin.as_bytes()))
-                .join()
-                .expect("Panic while writing to child process stdin")
-        })
-        .expect("io error while writing to child process stdin");
+    match entry {
+        Some(result) => Ok(Some(result.entry.into_owned())),
+        None => Err(ConsoleError::HistoryEventError(event.to_string())),
+    }
+}
 
-        let output = child.wait_with_output().expect("TODO");
+/// Look up the command called `name` in `commands`. If `case_insensitive`, a
+/// command whose name only differs in case is found when there's no exact
+/// match.
+pub(crate) fn find_command<'a>(
+    commands: &'a CommandMap,
+    name: &str,
+    case_insensitive: bool,
+) -> Option<&'a dyn Command> {
+    let cmd = match commands.get(name) {
+        Some(cmd) => cmd,
+        None if case_insensitive => {
+            let name = name.to_lowercase();
+            commands
+                .iter()
+                .find(|(k, _)| k.to_lowercase() == name)
+                .map(|(_, cmd)| cmd)?
+        }
+        None => return None,
+    };
 
-        // This avoids the pipeline and just goes to the console process's
-        // stderr.
-        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    Some(cmd.as_ref())
+}
 
-        write!(stdout, "{}", String::from_utf8_lossy(&output.stdout))
-            .map_err(|e| format!("IO error {e}"))?;
+/// Check that `cmd`'s parser is well-formed, turning the panic of a failed
+/// assertion into an error.
+fn validate_command(cmd: &dyn Command) -> Result<(), ConsoleError> {
+    // The parser is dropped if it panics, so it can't be seen half-checked
+    let parser = cmd.get_parser();
+    let check = std::panic::AssertUnwindSafe(move || parser.debug_assert());
+    std::panic::catch_unwind(check).map_err(|panic| {
+        let message = match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => panic
+                .downcast_ref::<&str>()
+                .map_or("invalid parser".to_string(), |message| message.to_string()),
+        };
+        ConsoleError::InvalidCommandError(cmd.get_name(), message)
+    })
+}
 
-        Ok(())
+/// Print how long each stage of a pipeline took to stderr.
+pub(crate) fn report_timings(timings: &[(String, Duration)]) {
+    for (name, duration) in timings {
+        eprintln!("{name}: {}ms", duration.as_millis());
     }
+}
 
-    pub fn add_command(self, cmd: Box<dyn Command>) -> Self {
-        self.commands.borrow_mut().insert(cmd.get_name(), cmd);
-        self
+/// The result of successfully parsing a command's arguments.
+enum ParsedArgs {
+    Matches(clap::ArgMatches),
+    /// The command was asked to display its help or version, which is output
+    /// rather than an error.
+    Text(String),
+}
+
+/// Parse `tokens`, starting with the command's name, with `parser`. If they
+/// are invalid, the error includes the command's `usage_hint`, if any.
+fn parse_args(
+    parser: clap::Command,
+    tokens: &[String],
+    usage_hint: Option<String>,
+) -> Result<ParsedArgs, ConsoleError> {
+    use clap::error::ErrorKind;
+
+    match parser.try_get_matches_from(tokens) {
+        Ok(matches) => Ok(ParsedArgs::Matches(matches)),
+        Err(e) if matches!(e.kind(), ErrorKind::DisplayHelp | ErrorKind::DisplayVersion) => {
+            Ok(ParsedArgs::Text(e.render().to_string()))
+        }
+        Err(e) => {
+            let mut message = e.render().to_string().trim_end().to_string();
+            // Not for errors like a missing subcommand, that show the help
+            if let Some(hint) = usage_hint.filter(|_| e.use_stderr()) {
+                message.push('\n');
+                message.push_str(&hint);
+            }
+            Err(ConsoleError::InvalidArgumentsError(message))
+        }
     }
 }
 
-impl Default for Console {
-    fn default() -> Self {
-        Self {
-            prompt: "> ".to_string(),
-            commands: Rc::new(RefCell::new(HashMap::new())),
+/// Split `pipeline` at each unquoted `|`, lexing backslashes as `backslash`
+/// says, and return each command line with its byte range in `pipeline`, e.g.
+/// to point at the one that's wrong.
+fn split_pipeline(pipeline: &str, backslash: lexer::Backslash) -> Vec<(Range<usize>, &str)> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut command_lines = vec![];
+    let mut last_end_idx = 0;
+    for (idx, ch) in pipeline.char_indices() {
+        match (quote, ch) {
+            _ if escaped => escaped = false,
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (None | Some('"'), '\\') if backslash == lexer::Backslash::Escape => escaped = true,
+            (None, '\'' | '"') => quote = Some(ch),
+            (None, '|') => {
+                command_lines.push((last_end_idx..idx, &pipeline[last_end_idx..idx]));
+                last_end_idx = idx + 1
+            }
+            _ => (),
+        }
+    }
+    // Last one
+    command_lines.push((last_end_idx..pipeline.len(), &pipeline[last_end_idx..]));
+
+    command_lines
+}
+
+impl Console {
+    /// Run the interactive loop until EOF, at which point `0` is returned.
+    /// Only errors that prevent the console from continuing are returned;
+    /// errors in individual command lines are reported on stderr.
+    pub fn cmd_loop(&mut self) -> Result<i32, ConsoleError> {
+        let rl = self.create_editor()?;
+        self.cmd_loop_with_editor(rl)
+    }
+
+    /// Run the interactive loop like [`Console::cmd_loop`], reading lines with
+    /// `editor`, e.g. to give it custom key bindings or create an external
+    /// printer from it first. The console attaches its own helper and loads
+    /// the history file, but the editor's configuration, such as its edit
+    /// mode, is kept, except that history is always added by the console.
+    pub fn cmd_loop_with_editor(&mut self, mut editor: ConsoleEditor) -> Result<i32, ConsoleError> {
+        self.prepare_editor(&mut editor)?;
+        let result = self.interact(&mut editor);
+        self.exit();
+
+        result
+    }
+
+    fn interact(&mut self, rl: &mut ConsoleEditor) -> Result<i32, ConsoleError> {
+        self.set_interactive(true);
+        if let Some(status) = self.run_startup_commands()? {
+            return Ok(status);
+        }
+
+        while let Some(line) = self.next_line(rl)? {
+            let result = self.run_line(&line);
+            self.report_line_error(result)?;
+        }
+
+        Ok(0)
+    }
+
+    /// Run the startup commands, returning the status to end the interactive
+    /// loop with if one of them exited or aborted startup.
+    fn run_startup_commands(&mut self) -> Result<Option<i32>, ConsoleError> {
+        for line in self.startup_commands.clone() {
+            let result = self.run_line(&line);
+            self.report_line_error(result)?;
+            if let Some(status) = self.startup_stop() {
+                return Ok(Some(status));
+            }
+        }
+
+        Ok(None)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn startup_commands(&self) -> &[String] {
+        &self.startup_commands
+    }
+
+    /// After a startup command has run, the status to end the interactive loop
+    /// with if it exited, or failed while startup errors abort.
+    pub(crate) fn startup_stop(&mut self) -> Option<i32> {
+        let failed = self.abort_on_startup_error && self.last_status != 0;
+        (self.take_exit_request() || failed).then_some(self.last_status)
+    }
+
+    /// Run each line of `script` in turn, as if typed at the prompt. Blank
+    /// lines and lines starting with `#` are skipped, and a line ending in a
+    /// backslash or an open quote continues on the next one. Errors in
+    /// individual lines are reported on stderr without stopping the script,
+    /// but `exit` at the top level ends it.
+    ///
+    /// Returns the exit status of the last line run, suitable for passing to
+    /// [`std::process::exit`].
+    pub fn run_script(&mut self, script: impl std::io::BufRead) -> Result<i32, ConsoleError> {
+        self.last_status = 0;
+        self.run_script_lines(script)
+    }
+
+    /// Run the lines of `script` like [`Console::run_script`], returning the
+    /// status of the last one, or 0 if there are none, but leave the last
+    /// status as it was until a line runs.
+    fn run_script_lines(&mut self, script: impl std::io::BufRead) -> Result<i32, ConsoleError> {
+        let mut status = 0;
+        let mut logical_line = String::new();
+        for line in script.lines() {
+            let line = line.map_err(|e| ConsoleError::ScriptReadError(e.to_string()))?;
+            logical_line.push_str(&line);
+            if prepare_continuation(&mut logical_line, self.backslash()) {
+                continue;
+            }
+
+            let trimmed = logical_line.trim_start();
+            if !trimmed.is_empty() && !trimmed.starts_with('#') {
+                let result = self.run_line(&logical_line);
+                self.report_line_error(result)?;
+                status = self.last_status;
+                if self.take_exit_request() || self.pending_control.is_some() {
+                    return Ok(status);
+                }
+            }
+            logical_line.clear();
+        }
+
+        // The script ended partway through a line
+        if !logical_line.is_empty() {
+            let result = self.run_line(&logical_line);
+            self.report_line_error(result)?;
+            status = self.last_status;
+        }
+
+        Ok(status)
+    }
+
+    /// Parse and run a single command line, which may be a pipeline.
+    ///
+    /// The exit status of the line is recorded and available from
+    /// [`Console::last_status`]: `0` on success, `2` if a command's arguments
+    /// couldn't be parsed, in which case the parser's message is returned as an
+    /// [`ConsoleError::InvalidArgumentsError`], and `1` for any other error. A
+    /// command's `--help` or `--version` output is passed down the pipeline
+    /// like any other output. A line ending in `> /dev/null` (`> NUL` on
+    /// Windows) runs without printing its output, though errors are still
+    /// reported.
+    pub fn run_line(&mut self, line: &str) -> Result<(), ConsoleError> {
+        let line = self.rewrite_line(line);
+        let line = match self.substitute_commands(&line) {
+            Ok(line) => line,
+            Err(e) => return self.record_status(Err(e)),
+        };
+        self.trace_line(&line);
+        let (line, print) = match strip_null_redirect(&line, self.backslash()) {
+            Some(rest) => (rest.to_string(), false),
+            None => (line, true),
+        };
+
+        let result = {
+            let commands = self.command_set();
+            let command_set = commands.borrow();
+
+            match self.parse_pipeline(&command_set, &line) {
+                Ok(Parsed::Pipeline(runnables)) => self.run_runnables(runnables, print),
+                Ok(Parsed::Finished(status)) => Ok(status),
+                Err(e) => Err(e),
+            }
+        };
+        self.apply_pending_control();
+
+        self.record_status(result)
+    }
+
+    /// The exit status of the last line run. See [`Console::run_line`].
+    pub fn last_status(&self) -> i32 {
+        self.last_status
+    }
+
+    /// The registered commands. The console's commands can't be changed, e.g.
+    /// by entering a sub-console, while they're borrowed, so the result
+    /// shouldn't be held on to while running lines.
+    pub fn commands(&self) -> Commands<'_> {
+        Commands(self.commands.borrow())
+    }
+
+    /// Whether `name`, or what it expands to if it's an alias, is a registered
+    /// command, ignoring case if the console is case-insensitive. Built-ins
+    /// and external commands aren't registered.
+    pub fn has_command(&self, name: &str) -> bool {
+        let word = lexer::Word {
+            text: name.to_string(),
+            quoted: false,
+        };
+        let Ok(words) = self.expand_aliases(vec![word]) else {
+            return false;
+        };
+        words.first().is_some_and(|word| {
+            find_command(&self.commands.borrow(), &word.text, self.case_insensitive).is_some()
+        })
+    }
+
+    /// Add `cmds` while `f` runs, e.g. for a plugin's commands that are only
+    /// available while it's active, then remove them again, even if `f`
+    /// panics. Commands they replaced while they were added are restored. If
+    /// `f` enters a sub-console, they're still removed from the console they
+    /// were added to.
+    pub fn with_scoped_commands<R>(
+        &mut self,
+        cmds: Vec<Box<dyn Command>>,
+        f: impl FnOnce(&mut Console) -> R,
+    ) -> R {
+        let depth = self.outer_consoles.len();
+        let mut replaced = vec![];
+        for cmd in cmds {
+            let name = cmd.get_name();
+            let previous = self.commands.borrow_mut().insert(name.clone(), cmd);
+            replaced.push((name, previous));
+        }
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self)));
+
+        let mut current = self.commands.borrow_mut();
+        let open = self.outer_consoles.len();
+        let commands = match self.outer_consoles.get_mut(depth) {
+            // `f` entered a sub-console, which set the commands aside
+            Some((_, commands)) => Some(commands),
+            None if depth == open => Some(&mut *current),
+            // `f` left the sub-console, whose commands are gone with it
+            None => None,
+        };
+        if let Some(commands) = commands {
+            // In reverse, in case a scoped command replaced another
+            for (name, previous) in replaced.into_iter().rev() {
+                match previous {
+                    Some(cmd) => commands.insert(name, cmd),
+                    None => commands.remove(&name),
+                };
+            }
+        }
+        drop(current);
+
+        result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+    }
+
+    /// The directory external commands run in, and which scripts run with
+    /// `source` are found relative to. It starts as the process's current
+    /// directory, and is changed with the built-in `cd`, which doesn't change
+    /// the process's own.
+    pub fn current_dir(&self) -> &Path {
+        &self.current_dir
+    }
+
+    /// Set the session variable `name` to `value`. Commands read and change
+    /// the variables through [`ExecContext::vars`], and their names are
+    /// completed after `$` at the prompt.
+    pub fn set_var(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.vars.insert(name.into(), value.into());
+    }
+
+    /// The value of the session variable `name`, if it's set.
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// How many times each command has run, successfully or not, by name.
+    /// Built-ins and external commands are counted too.
+    pub fn command_stats(&self) -> HashMap<String, u64> {
+        self.command_stats.clone()
+    }
+
+    pub(crate) fn command_set(&self) -> CommandSet {
+        Rc::clone(&self.commands)
+    }
+
+    pub(crate) fn create_editor(&self) -> Result<ConsoleEditor, ConsoleError> {
+        let rl_config = rustyline::Config::builder()
+            .check_cursor_position(true) // Prevent overwriting of stdout
+            .completion_type(self.completion_type)
+            .edit_mode(self.edit_mode)
+            .build();
+        Ok(rustyline::Editor::with_config(rl_config)?)
+    }
+
+    /// Update the names of the session variables that the completer sees.
+    fn share_var_names(&self) {
+        self.completion_vars
+            .replace(self.vars.keys().cloned().collect());
+    }
+
+    /// The completer for lines typed at the prompt.
+    fn completer(&self) -> CommandCompleter {
+        self.share_var_names();
+        CommandCompleter::new(self.command_set())
+            .with_aliases(Rc::clone(&self.aliases))
+            .with_current_dir(Rc::clone(&self.completion_dir))
+            .with_vars(Rc::clone(&self.completion_vars))
+            .with_case_insensitive(self.case_insensitive)
+            .with_help(self.completion_help)
+            .with_externals(self.externals)
+            .with_external_allowlist(self.external_allowlist.clone())
+            .with_filter(self.completion_filter.clone())
+    }
+
+    /// Complete `line` with the cursor at byte offset `pos`, as pressing tab
+    /// at the prompt would, e.g. for another front-end. Returns where the
+    /// completed word starts, which each candidate's replacement replaces up
+    /// to `pos`, and the candidates.
+    pub fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+    ) -> Result<(usize, Vec<rustyline::completion::Pair>), ConsoleError> {
+        use rustyline::completion::Completer as _;
+
+        let history = rustyline::history::DefaultHistory::new();
+        let ctx = rustyline::Context::new(&history);
+        Ok(self.completer().complete(line, pos, &ctx)?)
+    }
+
+    /// Attach the console's helper to `rl` and load the history file into it.
+    /// The editor is otherwise left as it was configured.
+    pub(crate) fn prepare_editor(&self, rl: &mut ConsoleEditor) -> Result<(), ConsoleError> {
+        // Commands that use `colored` will follow the same decision
+        colored::control::set_override(self.color_enabled());
+
+        // History is added manually so continued lines form one entry
+        rl.set_auto_add_history(false);
+        rl.set_helper(Some(ConsoleHelper {
+            completer: self.completer(),
+            highlighter: CommandHighlighter::new(self.command_set(), Rc::clone(&self.aliases))
+                .with_case_insensitive(self.case_insensitive)
+                .with_enabled(self.highlighting && self.color_enabled())
+                .with_dim_hints(self.color_enabled()),
+            history_hinter: self.history_hints.then(HistoryHinter::new),
+        }));
+
+        if let Some(idle) = &self.idle_timeout {
+            idle.track_activity(rl);
+        }
+
+        if let Some(path) = &self.history_file {
+            match rl.load_history(path) {
+                // There's no history yet
+                Err(ReadlineError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => (),
+                result => result?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Read the next line to run from `rl` and add it to the history, or
+    /// return `None` at EOF. History events like `!!` are expanded here.
+    pub(crate) fn next_line(
+        &mut self,
+        rl: &mut ConsoleEditor,
+    ) -> Result<Option<String>, ConsoleError> {
+        loop {
+            if self.take_exit_request() {
+                return Ok(None);
+            }
+            self.share_var_names();
+
+            let readline = match self.read_before_idle_timeout(rl) {
+                Ok(Some(o)) => o,
+                Ok(None) => return Ok(None),
+                Err(e) => match e {
+                    ReadlineError::Eof => return Ok(None),
+                    _ => return Err(ConsoleError::from(e)),
+                },
+            };
+
+            // Rerun an earlier line with `!!` or `!n`
+            let readline = match expand_history_event(&readline, rl.history()) {
+                Ok(Some(expanded)) => {
+                    println!("{expanded}");
+                    expanded
+                }
+                Ok(None) => readline,
+                Err(e) => {
+                    self.last_status = 1;
+                    self.report_error(&e);
+                    continue;
+                }
+            };
+
+            if self.add_history && !readline.trim().is_empty() && !self.is_secret(&readline) {
+                rl.add_history_entry(readline.as_str())?;
+                if let Some(path) = &self.history_file {
+                    if let Err(e) = rl.append_history(path) {
+                        eprintln!("Error saving history to `{}`: {e}", path.display());
+                    }
+                }
+            }
+
+            if self.is_comment(&readline) {
+                continue;
+            }
+
+            return Ok(Some(readline));
+        }
+    }
+
+    /// Enter or leave a sub-console, if a command asked to while running the
+    /// last line.
+    pub(crate) fn apply_pending_control(&mut self) {
+        // Lines run by `source` finish while the command set is still in use
+        // by the line that ran it, which applies the change instead
+        if self.commands.try_borrow_mut().is_err() {
+            return;
+        }
+
+        match self.pending_control.take() {
+            Some(Control::EnterSubConsole(sub)) => {
+                let commands = sub
+                    .commands
+                    .into_iter()
+                    .map(|c| (c.get_name(), c))
+                    .collect();
+                // The completer shares the command set, so it's replaced in place
+                let outer_commands = std::mem::replace(&mut *self.commands.borrow_mut(), commands);
+                let outer_prompt = std::mem::replace(&mut self.prompt, sub.prompt);
+                self.outer_consoles.push((outer_prompt, outer_commands));
+            }
+            Some(Control::ExitSubConsole) => match self.outer_consoles.pop() {
+                Some((prompt, commands)) => {
+                    self.prompt = prompt;
+                    *self.commands.borrow_mut() = commands;
+                }
+                None => self.exit_requested = true,
+            },
+            Some(Control::StopPipeline) | None => (),
+        }
+    }
+
+    /// Whether `exit` was run at the top level, which should end the
+    /// interactive loop or script. The request is cleared.
+    fn take_exit_request(&mut self) -> bool {
+        std::mem::take(&mut self.exit_requested)
+    }
+
+    /// The context to run a command with, on the given streams.
+    pub(crate) fn exec_context<'a>(
+        &'a mut self,
+        stdin: &'a str,
+        stdout: &'a mut dyn std::fmt::Write,
+        stderr: &'a mut dyn std::fmt::Write,
+    ) -> ExecContext<'a> {
+        ExecContext {
+            stdin,
+            stdout,
+            stderr,
+            current_dir: &self.current_dir,
+            vars: &mut self.vars,
+            confirm_handler: self.confirm_handler.as_deref(),
+            interactive: self.interactive,
+        }
+    }
+
+    /// Record whether the console is reading lines from the user, who can be
+    /// asked to confirm things.
+    pub(crate) fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// Call the exit hook, if it hasn't been called yet.
+    pub(crate) fn exit(&mut self) {
+        self.interactive = false;
+        if let Some(on_exit) = self.on_exit.take() {
+            on_exit();
+        }
+    }
+
+    /// Record the exit status of a line that finished with `result`.
+    pub(crate) fn record_status(
+        &mut self,
+        result: Result<i32, ConsoleError>,
+    ) -> Result<(), ConsoleError> {
+        self.last_status = match result {
+            Ok(status) => status,
+            Err(ConsoleError::InvalidArgumentsError(_)) => 2,
+            Err(_) => 1,
+        };
+
+        result.map(|_| ())
+    }
+
+    /// Report the error a line finished with, if any, on stderr. Only errors
+    /// that prevent the console from continuing are returned.
+    pub(crate) fn report_line_error(
+        &self,
+        result: Result<(), ConsoleError>,
+    ) -> Result<(), ConsoleError> {
+        match result {
+            Err(e @ ConsoleError::StdoutWriteError) => Err(e),
+            Err(e) => {
+                self.report_error(&e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    fn report_error(&self, error: &ConsoleError) {
+        log::debug!("command line failed: {error}");
+        match error {
+            ConsoleError::UnrecognizedCommand(name) => match self.suggest_command(name) {
+                Some(suggestion) => eprintln!("{error}. Did you mean `{suggestion}`?"),
+                None => eprintln!("{error}"),
+            },
+            ConsoleError::CommandError(..)
+            | ConsoleError::BrokenPipeError(..)
+            | ConsoleError::ExternalCommandNotFoundError(_)
+            | ConsoleError::ExternalSpawnError(..) => {
+                eprintln!("{}", error.to_string().red())
+            }
+            _ => eprintln!("{error}"),
+        }
+    }
+
+    /// Parse every command in `line`, looking them up in `command_set`. If one
+    /// fails, then the pipeline shouldn't run at all.
+    pub(crate) fn parse_pipeline<'a>(
+        &mut self,
+        command_set: &'a CommandMap,
+        line: &str,
+    ) -> Result<Parsed<'a>, ConsoleError> {
+        match self.parse_runnables(command_set, line) {
+            Ok(runnables) => Ok(Parsed::Pipeline(runnables)),
+            Err(ConsoleError::UnrecognizedCommand(name)) => {
+                let handled = self
+                    .command_not_found
+                    .as_mut()
+                    .is_some_and(|hook| hook(&name));
+                if handled {
+                    return Ok(Parsed::Finished(0));
+                }
+                Err(ConsoleError::UnrecognizedCommand(name))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Parse every command in `line` into a stage of the pipeline, without
+    /// running anything.
+    fn parse_runnables<'a>(
+        &self,
+        command_set: &'a CommandMap,
+        line: &str,
+    ) -> Result<VecDeque<Runnable<'a>>, ConsoleError> {
+        let mut runnables = VecDeque::new();
+
+        let command_lines = split_pipeline(line, self.backslash());
+        if let Some(max) = self
+            .max_pipeline_len
+            .filter(|&max| command_lines.len() > max)
+        {
+            return Err(ConsoleError::PipelineTooLongError(command_lines.len(), max));
+        }
+
+        for (span, command_line) in command_lines {
+            log::trace!("splitting command line {command_line:?} at {span:?}");
+            let words = lexer::split_with(command_line, self.backslash())
+                .ok_or_else(|| ConsoleError::LexingError(command_line.to_string()))?;
+            let words = self.expand_aliases(words)?;
+
+            let raw_cmd = words
+                .first()
+                .and_then(|word| find_command(command_set, &word.text, self.case_insensitive))
+                .filter(|cmd| cmd.wants_raw_args());
+            if let Some(cmd) = raw_cmd {
+                log::debug!("parsed command `{}` with raw arguments", cmd.get_name());
+                let args = words[1..].iter().map(|word| word.text.clone()).collect();
+                runnables.push_back(Runnable::Raw { cmd, args });
+                continue;
+            }
+
+            // `2>&1` sends an external command's stderr down the pipeline too
+            let external = words.first().is_some_and(|word| word.text.starts_with('!'));
+            let before = words.len();
+            let words: Vec<_> = words
+                .into_iter()
+                .filter(|word| !(external && !word.quoted && word.text == "2>&1"))
+                .collect();
+            let merge_stderr = words.len() != before;
+
+            let tokens = self.expand_words(&words)?;
+
+            if tokens.is_empty() {
+                return Err(ConsoleError::EmptyCommandLineError);
+            }
+            log::trace!("expanded command line: {tokens:?}");
+
+            // Handle possible external commands, prefixed by !
+            let (external_cmd, rest) = if tokens[0] == "!" {
+                // Standalone '!'
+                (tokens.get(1).map(|s| s.as_str()), &tokens[2..])
+            } else if tokens[0].chars().nth(0).is_some_and(|c| c == '!') {
+                // Command starts with '!'
+                (tokens.first().map(|s| &s[1..]), &tokens[1..])
+            } else {
+                // No '!'
+                (None, &[] as &[String])
+            };
+
+            let runnable = if let Some(program) = external_cmd {
+                if !self.externals {
+                    return Err(ConsoleError::ExternalCommandsDisabledError(
+                        program.to_string(),
+                    ));
+                }
+                if let Some(allowlist) = &self.external_allowlist {
+                    if !allowlist.contains(program) {
+                        return Err(ConsoleError::ExternalCommandNotAllowedError(
+                            program.to_string(),
+                        ));
+                    }
+                }
+                log::debug!("parsed external command `{program}`");
+                Runnable::External {
+                    name: program.to_string(),
+                    args: rest.to_vec(),
+                    piped: false,
+                    merge_stderr,
+                }
+            } else if let Some(cmd) = find_command(command_set, &tokens[0], self.case_insensitive) {
+                match parse_args(cmd.get_parser(), &tokens, cmd.usage_hint())? {
+                    ParsedArgs::Matches(args) => {
+                        log::debug!("parsed command `{}`", cmd.get_name());
+                        Runnable::Command { cmd, args }
+                    }
+                    ParsedArgs::Text(text) => Runnable::Text {
+                        name: tokens[0].clone(),
+                        text,
+                    },
+                }
+            } else if let Some(intrinsic) = Intrinsic::find(&tokens[0], self.case_insensitive) {
+                match parse_args(intrinsic.parser(), &tokens, None)? {
+                    ParsedArgs::Matches(args) => {
+                        log::debug!("parsed built-in command `{}`", intrinsic.name());
+                        Runnable::Intrinsic { intrinsic, args }
+                    }
+                    ParsedArgs::Text(text) => Runnable::Text {
+                        name: tokens[0].clone(),
+                        text,
+                    },
+                }
+            } else {
+                return Err(ConsoleError::UnrecognizedCommand(tokens[0].clone()));
+            };
+            if let Some(Runnable::External { piped, .. }) = runnables.back_mut() {
+                *piped = true;
+            }
+            runnables.push_back(runnable);
+        }
+
+        Ok(runnables)
+    }
+
+    /// Parse `line` as [`Console::run_line`] would, including running it
+    /// through the line rewriter, but instead of running it, describe each
+    /// stage of the pipeline that would run. Command substitutions aren't run
+    /// either, and so are left as they are. If the output would be discarded,
+    /// as with `> /dev/null`, that's described last.
+    pub fn dry_run_line(&mut self, line: &str) -> Result<Vec<String>, ConsoleError> {
+        let line = self.rewrite_line(line);
+        let (line, discarded) = match strip_null_redirect(&line, self.backslash()) {
+            Some(rest) => (rest.to_string(), true),
+            None => (line, false),
+        };
+        let command_set = self.commands.borrow();
+        let runnables = self.parse_runnables(&command_set, &line)?;
+
+        let mut descriptions: Vec<String> = runnables.iter().map(Runnable::describe).collect();
+        if discarded {
+            descriptions.push("output discarded".to_string());
+        }
+        Ok(descriptions)
+    }
+
+    /// Now that we know each command exists and has appropriate arguments, run
+    /// them, printing the output if `print`. Returns the exit status of the
+    /// pipeline.
+    fn run_runnables(
+        &mut self,
+        runnables: VecDeque<Runnable>,
+        print: bool,
+    ) -> Result<i32, ConsoleError> {
+        let streams = runnables.iter().any(|runnable| {
+            matches!(runnable, Runnable::Command { cmd, .. } if cmd.as_streaming().is_some())
+        });
+        if self.streaming && streams {
+            // The sink can't be borrowed from the console while it runs
+            let mut sink = self.output_sink.take();
+            let result = streaming::run_pipeline(self, runnables, &mut |line| {
+                if print {
+                    write_output(sink.as_deref_mut(), &format!("{line}\n"))
+                } else {
+                    Ok(())
+                }
+            });
+            self.output_sink = sink;
+            result?;
+            return Ok(0);
+        }
+
+        match self.run_pipeline(runnables, print)? {
+            Some(output) if print => self.print_output(&output)?,
+            _ => (),
+        }
+
+        Ok(0)
+    }
+
+    /// Run `cmd` as the last stage of a line, writing its output straight to
+    /// the output sink or stdout. See [`Command::live_output`].
+    pub(crate) fn run_live_stage(
+        &mut self,
+        cmd: &dyn Command,
+        args: clap::ArgMatches,
+        stdin: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut ctx = ExecContext {
+            stdin,
+            stdout: &mut LiveWriter(self.output_sink.as_deref_mut()),
+            stderr: &mut StderrWriter,
+            current_dir: &self.current_dir,
+            vars: &mut self.vars,
+            confirm_handler: self.confirm_handler.as_deref(),
+            interactive: self.interactive,
+        };
+        cmd.execute(args, &mut ctx)
+    }
+
+    /// Run the stages of a pipeline in series, passing the output from each
+    /// to the next, and return the output of the last. Returns `None` if a
+    /// stage stopped the pipeline, or if `live` and the last stage wrote its
+    /// output straight to the output, as with [`Command::live_output`].
+    fn run_pipeline(
+        &mut self,
+        mut runnables: VecDeque<Runnable>,
+        live: bool,
+    ) -> Result<Option<String>, ConsoleError> {
+        let in_pipeline = runnables.len() > 1;
+
+        let mut timings = vec![];
+        let mut previous_output = String::new();
+        let mut stage = 0;
+        while let Some(runnable) = runnables.pop_front() {
+            stage += 1;
+            let mut output_buf = String::new();
+            let start = self.timing().then(Instant::now);
+            let live = live
+                && runnables.is_empty()
+                && matches!(&runnable, Runnable::Command { cmd, .. } if cmd.live_output());
+            let (res, command_name) = match runnable {
+                Runnable::Command { cmd, args } if live => (
+                    self.run_live_stage(cmd, args, &previous_output),
+                    cmd.get_name(),
+                ),
+                runnable => self.run_stage(runnable, &previous_output, &mut output_buf),
+            };
+            if let Some(start) = start {
+                timings.push((command_name.clone(), start.elapsed()));
+            }
+            let stage = in_pipeline.then_some(stage);
+            let output = self.finish_stage(res, command_name, stage, output_buf);
+            match output? {
+                // Live output has already been printed, unfiltered and untransformed
+                Some(_) if live => {
+                    report_timings(&timings);
+                    return Ok(None);
+                }
+                Some(output) => previous_output = output,
+                None => {
+                    report_timings(&timings);
+                    return Ok(None);
+                }
+            }
+        }
+        report_timings(&timings);
+
+        Ok(Some(previous_output))
+    }
+
+    /// Apply the line rewriter, if any, to `line`, then replace any ANSI-C
+    /// quoted strings in it if enabled.
+    pub(crate) fn rewrite_line(&mut self, line: &str) -> String {
+        let line = match &mut self.line_rewriter {
+            Some(rewriter) => rewriter(line.to_string()),
+            None => line.to_string(),
+        };
+        if self.ansi_c_quoting {
+            expand::ansi_c_quotes(&line, self.backslash())
+        } else {
+            line
+        }
+    }
+
+    /// Run the command substitutions in `line`, replacing each with its
+    /// output.
+    pub(crate) fn substitute_commands(&mut self, line: &str) -> Result<String, ConsoleError> {
+        let backslash = self.backslash();
+        expand::substitute_commands(line, backslash, &mut |command| self.capture_line(command))
+    }
+
+    /// Run a command line like [`Console::run_line`], but return its output
+    /// instead of printing it. A line that doesn't run, e.g. because the
+    /// command-not-found hook handled it, has no output.
+    ///
+    /// Like a subshell, the line can't enter or leave a sub-console.
+    pub(crate) fn capture_line(&mut self, line: &str) -> Result<String, ConsoleError> {
+        let line = self.substitute_commands(line)?;
+
+        let commands = self.command_set();
+        let command_set = commands.borrow();
+
+        let output = match self.parse_pipeline(&command_set, &line)? {
+            Parsed::Pipeline(runnables) => self.run_pipeline(runnables, false)?.unwrap_or_default(),
+            Parsed::Finished(_) => String::new(),
+        };
+        self.pending_control = None;
+        Ok(output)
+    }
+
+    /// Run a single stage of a pipeline, returning its result and the name of
+    /// the command that ran.
+    pub(crate) fn run_stage(
+        &mut self,
+        runnable: Runnable,
+        stdin: &str,
+        stdout: &mut String,
+    ) -> (Result<(), Box<dyn std::error::Error>>, String) {
+        let max = self.max_output_bytes;
+        let mut stdout = LimitedWriter {
+            output: stdout,
+            max,
+            exceeded: false,
+        };
+        let (result, name) = match runnable {
+            Runnable::External {
+                name,
+                args,
+                piped,
+                merge_stderr,
+            } => (
+                self.run_external_command(
+                    &name,
+                    &args.iter().map(|s| s.as_str()).collect(),
+                    piped,
+                    merge_stderr,
+                    stdin,
+                    stdout.output,
+                ),
+                name,
+            ),
+            Runnable::Command { cmd, args } => {
+                let mut stderr = StderrWriter;
+                let mut ctx = self.exec_context(stdin, &mut stdout, &mut stderr);
+                (cmd.execute(args, &mut ctx), cmd.get_name())
+            }
+            Runnable::Raw { cmd, args } => (
+                cmd.execute_raw(
+                    &args,
+                    &mut self.exec_context(stdin, &mut stdout, &mut StderrWriter),
+                ),
+                cmd.get_name(),
+            ),
+            Runnable::Intrinsic { intrinsic, args } => (
+                self.run_intrinsic(intrinsic, args, stdin, &mut stdout, &mut StderrWriter),
+                intrinsic.name().to_string(),
+            ),
+            Runnable::Text { name, text } => {
+                stdout.output.push_str(&text);
+                (Ok(()), name)
+            }
+        };
+
+        // The command most likely failed because it couldn't write
+        match max.filter(|_| stdout.exceeded) {
+            Some(max) => (
+                Err(Box::new(ConsoleError::OutputLimitError(name.clone(), max))),
+                name,
+            ),
+            None => (result, name),
+        }
+    }
+
+    /// Turn the `result` of a pipeline stage into a `ConsoleError`, or filter
+    /// its `output` so it's ready to be passed to the next stage. Returns
+    /// `None` if the stage stopped the pipeline. `stage` is the number of the
+    /// stage, counting from 1, if the pipeline has more than one.
+    pub(crate) fn finish_stage(
+        &mut self,
+        result: Result<(), Box<dyn std::error::Error>>,
+        command_name: String,
+        stage: Option<usize>,
+        output: String,
+    ) -> Result<Option<String>, ConsoleError> {
+        *self.command_stats.entry(command_name.clone()).or_default() += 1;
+
+        if let Err(error_msg) = result {
+            let error_msg = match error_msg.downcast::<Control>() {
+                Ok(control) => {
+                    log::debug!("command `{command_name}` stopped the pipeline: {control}");
+                    // The command set is in use until the line finishes, so
+                    // sub-consoles are entered and left afterwards
+                    if !matches!(*control, Control::StopPipeline) {
+                        self.pending_control = Some(*control);
+                    }
+                    return Ok(None);
+                }
+                Err(error_msg) => error_msg,
+            };
+            log::debug!("command `{command_name}` failed: {error_msg}");
+
+            // Errors from the console itself, e.g. in starting an external
+            // command, are reported as they are
+            let mut error = match error_msg.downcast::<ConsoleError>() {
+                Ok(error) => *error,
+                Err(error_msg) => {
+                    ConsoleError::CommandError(command_name.clone(), error_msg.to_string())
+                }
+            };
+
+            // If this is a pipeline of multiple commands, then wrap the
+            // current command's error in a pipeline error.
+            if let Some(stage) = stage {
+                error = ConsoleError::BrokenPipeError(stage, command_name, Box::new(error));
+            }
+
+            return Err(error);
+        }
+
+        log::debug!(
+            "command `{command_name}` succeeded with {} bytes of output",
+            output.len()
+        );
+        Ok(Some(self.filter_output(output)))
+    }
+
+    /// Apply the output filter, if any, to `output`.
+    pub(crate) fn filter_output(&self, output: String) -> String {
+        match &self.output_filter {
+            Some(filter) => filter(output),
+            None => output,
+        }
+    }
+
+    /// Whether `line`, typed at the prompt, is a comment to skip.
+    fn is_comment(&self, line: &str) -> bool {
+        let first = line.trim_start_matches(lexer::is_blank).chars().next();
+        first.is_some_and(|first| Some(first) == self.comment_char)
+    }
+
+    /// Whether any part of `line` runs a [secret](Command::secret) command:
+    /// any stage of its pipeline, or any command substitution in it. Such a
+    /// line shouldn't be added to the history.
+    fn is_secret(&self, line: &str) -> bool {
+        // Substitutions are checked rather than run
+        let mut secret = false;
+        let line = expand::substitute_commands(line, self.backslash(), &mut |command| {
+            secret |= self.is_secret(command);
+            Ok(String::new())
+        })
+        .unwrap_or_else(|_| line.to_string());
+        if secret {
+            return true;
+        }
+
+        split_pipeline(&line, self.backslash())
+            .into_iter()
+            .any(|(_, command_line)| {
+                // An incomplete line is still checked, word by word
+                let words =
+                    lexer::split_with(command_line, self.backslash()).unwrap_or_else(|| {
+                        command_line
+                            .split(lexer::is_blank)
+                            .filter(|s| !s.is_empty())
+                            .map(|text| lexer::Word {
+                                text: text.to_string(),
+                                quoted: false,
+                            })
+                            .collect()
+                    });
+                self.runs_secret_command(words)
+            })
+    }
+
+    /// Whether the command line made of `words` runs a [secret](Command::secret)
+    /// command, by name, through an alias or with `repeat`.
+    fn runs_secret_command(&self, words: Vec<lexer::Word>) -> bool {
+        let Ok(words) = self.expand_aliases(words) else {
+            return false;
+        };
+        let Some(name) = words.first().map(|word| word.text.as_str()) else {
+            return false;
+        };
+
+        match find_command(&self.commands.borrow(), name, self.case_insensitive) {
+            Some(cmd) => cmd.secret(),
+            // `repeat` runs the command after its count
+            None if Intrinsic::find(name, self.case_insensitive) == Some(Intrinsic::Repeat) => {
+                words.len() > 2 && self.runs_secret_command(words[2..].to_vec())
+            }
+            None => false,
+        }
+    }
+
+    /// How backslashes in command lines are lexed.
+    pub(crate) fn backslash(&self) -> lexer::Backslash {
+        if self.windows_paths {
+            lexer::Backslash::Literal
+        } else {
+            lexer::Backslash::Escape
+        }
+    }
+
+    /// Print `line` to stderr before it runs, if tracing is enabled.
+    pub(crate) fn trace_line(&self, line: &str) {
+        if self.trace {
+            eprintln!("+ {line}");
+        }
+    }
+
+    /// Whether the time taken by each stage of a pipeline is measured.
+    pub(crate) fn timing(&self) -> bool {
+        self.timing
+    }
+
+    /// Print the output at the end of the pipeline, or pass it to the output
+    /// sink
+    pub(crate) fn print_output(&mut self, output: &str) -> Result<(), ConsoleError> {
+        match &mut self.final_output_transform {
+            Some(transform) => {
+                let output = transform(output.to_string());
+                write_output(self.output_sink.as_deref_mut(), &output)
+            }
+            None => write_output(self.output_sink.as_deref_mut(), output),
+        }
+    }
+
+    /// Whether the console emits colored output. Unless set with
+    /// [`Console::with_color`], this honors the `NO_COLOR`, `CLICOLOR` and
+    /// `CLICOLOR_FORCE` environment variables, falling back to whether stdout
+    /// is a terminal.
+    pub fn color_enabled(&self) -> bool {
+        self.color.unwrap_or_else(color_from_env)
+    }
+
+    /// The rendered usage string of the command called `name`, or `None` if
+    /// there is no such command.
+    pub fn command_usage(&self, name: &str) -> Option<String> {
+        self.parser_for(name)
+            .map(|parser| parser.bin_name(name).render_usage().to_string())
+    }
+
+    /// The arguments declared by the command called `name`, or `None` if there
+    /// is no such command.
+    pub fn command_args(&self, name: &str) -> Option<Vec<ArgInfo>> {
+        self.parser_for(name)
+            .map(|parser| parser.get_arguments().map(ArgInfo::from).collect())
+    }
+
+    /// The parser of the registered command or intrinsic called `name`.
+    fn parser_for(&self, name: &str) -> Option<clap::Command> {
+        match self.commands.borrow().get(name) {
+            Some(cmd) => Some(cmd.get_parser()),
+            None => Intrinsic::from_name(name).map(Intrinsic::parser),
+        }
+    }
+
+    fn run_intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        args: clap::ArgMatches,
+        _stdin: &str,
+        stdout: &mut dyn std::fmt::Write,
+        _stderr: &mut dyn std::fmt::Write,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match intrinsic {
+            Intrinsic::Alias => {
+                let Some(definitions) = args.get_many::<String>("definition") else {
+                    for (name, value) in self.aliases.borrow().iter() {
+                        writeln!(
+                            stdout,
+                            "{}",
+                            intrinsic::alias_definition(name, value, self.backslash())
+                        )?;
+                    }
+                    return Ok(());
+                };
+
+                for definition in definitions {
+                    match definition.split_once('=') {
+                        Some(("", _)) => return Err(format!("invalid alias `{definition}`").into()),
+                        Some((name, value)) => {
+                            let (name, value) = (name.to_string(), value.to_string());
+                            self.aliases.borrow_mut().insert(name, value);
+                        }
+                        None => match self.aliases.borrow().get(definition) {
+                            Some(value) => writeln!(
+                                stdout,
+                                "{}",
+                                intrinsic::alias_definition(definition, value, self.backslash())
+                            )?,
+                            None => return Err(format!("no such alias `{definition}`").into()),
+                        },
+                    }
+                }
+            }
+            Intrinsic::Stats => {
+                let stats: BTreeMap<_, _> = self.command_stats.iter().collect();
+                for (name, count) in stats {
+                    writeln!(stdout, "{name}: {count}")?;
+                }
+            }
+            Intrinsic::Unalias => {
+                for name in args.get_many::<String>("name").into_iter().flatten() {
+                    if self.aliases.borrow_mut().remove(name).is_none() {
+                        return Err(format!("no such alias `{name}`").into());
+                    }
+                }
+            }
+            Intrinsic::Cd => {
+                let dir = match args.get_one::<String>("dir").map(String::as_str) {
+                    Some("-") => {
+                        let previous = self.previous_dir.clone().ok_or("no previous directory")?;
+                        writeln!(stdout, "{}", previous.display())?;
+                        previous
+                    }
+                    Some(dir) => self.current_dir.join(dir),
+                    None => std::env::home_dir().ok_or("no home directory")?,
+                };
+                let dir = dir
+                    .canonicalize()
+                    .map_err(|e| format!("{}: {e}", dir.display()))?;
+                if !dir.is_dir() {
+                    return Err(format!("{}: not a directory", dir.display()).into());
+                }
+
+                self.completion_dir.replace(dir.clone());
+                let previous = std::mem::replace(&mut self.current_dir, dir);
+                self.previous_dir = Some(previous);
+            }
+            Intrinsic::Config => {
+                let history_file = match &self.history_file {
+                    Some(path) => path.display().to_string(),
+                    None => "none".to_string(),
+                };
+                let color = if self.color_enabled() { "on" } else { "off" };
+
+                writeln!(stdout, "prompt: {:?}", self.prompt)?;
+                writeln!(stdout, "history file: {history_file}")?;
+                writeln!(stdout, "color: {color}")?;
+                writeln!(stdout, "commands: {}", self.commands.borrow().len())?;
+            }
+            Intrinsic::Exit => return Err(Box::new(Control::ExitSubConsole)),
+            Intrinsic::Help => match args.get_one::<String>("command") {
+                Some(name) => match intrinsic::help_for(&self.commands, name) {
+                    Some(help) => write!(stdout, "{help}")?,
+                    None => return Err(format!("no such command `{name}`").into()),
+                },
+                None => write!(stdout, "{}", intrinsic::help_overview(&self.commands))?,
+            },
+            Intrinsic::Pwd => writeln!(stdout, "{}", self.current_dir.display())?,
+            Intrinsic::Repeat => {
+                if self.repeating {
+                    return Err("`repeat` can't be nested".into());
+                }
+                let count = args.get_one::<u32>("count").copied().unwrap_or_default();
+                // Quote each word, so that it reaches the command unchanged
+                let line = args
+                    .get_many::<String>("command")
+                    .into_iter()
+                    .flatten()
+                    .map(|word| expand::single_quote(word, self.backslash()))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+
+                self.repeating = true;
+                let result =
+                    (0..count).try_for_each(|_| -> Result<(), Box<dyn std::error::Error>> {
+                        let output = self.capture_line(&line)?;
+                        stdout.write_str(&output)?;
+                        Ok(())
+                    });
+                self.repeating = false;
+                result?;
+            }
+            Intrinsic::Source => {
+                let path = args.get_one::<String>("file").cloned().unwrap_or_default();
+                if self.source_depth >= MAX_SOURCE_DEPTH {
+                    return Err(format!("{path}: too many nested scripts").into());
+                }
+                let file = std::fs::File::open(self.current_dir.join(&path))
+                    .map_err(|e| format!("{path}: {e}"))?;
+
+                self.source_depth += 1;
+                let result = self.run_script_lines(std::io::BufReader::new(file));
+                self.source_depth -= 1;
+                // Like `.` in sh, it fails if the script's last line did
+                if result? != 0 {
+                    return Err(format!("{path}: the last line failed").into());
+                }
+            }
+            Intrinsic::Which => {
+                for name in args.get_many::<String>("name").into_iter().flatten() {
+                    let which = intrinsic::which(&self.commands, &self.aliases.borrow(), name);
+                    writeln!(stdout, "{which}")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the registered command or intrinsic closest to the unrecognized
+    /// `name`, if any is within the suggestion threshold.
+    fn suggest_command(&self, name: &str) -> Option<String> {
+        let command_set = self.commands.borrow();
+        let intrinsics = Intrinsic::ALL.iter().map(|i| i.name());
+
+        command_set
+            .keys()
+            .map(|k| k.as_str())
+            .chain(intrinsics)
+            .map(|candidate| (levenshtein(name, candidate), candidate))
+            .filter(|&(distance, _)| distance <= self.suggestion_threshold)
+            .min()
+            .map(|(_, candidate)| candidate.to_string())
+    }
+
+    /// Replace the first word of a command with the words of its alias, if it
+    /// is an unquoted alias name. The result is expanded again, so aliases may
+    /// refer to other aliases, but each alias is expanded at most once so that
+    /// e.g. `alias ls='!ls -F'` or a cycle of aliases terminate.
+    fn expand_aliases(
+        &self,
+        mut words: Vec<lexer::Word>,
+    ) -> Result<Vec<lexer::Word>, ConsoleError> {
+        let mut expanded = HashSet::new();
+        while let Some(first) = words.first().filter(|w| !w.quoted) {
+            let Some(value) = self.aliases.borrow().get(&first.text).cloned() else {
+                break;
+            };
+            if !expanded.insert(first.text.clone()) {
+                break;
+            }
+
+            let alias_words = lexer::split_with(&value, self.backslash())
+                .ok_or(ConsoleError::LexingError(value))?;
+            words.splice(..1, alias_words);
+        }
+
+        Ok(words)
+    }
+
+    /// Perform response file (if enabled), tilde and (if enabled) glob
+    /// expansion on the unquoted `words` of a command line. Like options,
+    /// expansions end at an unquoted `--`, after which words are taken
+    /// literally.
+    fn expand_words(&self, words: &[lexer::Word]) -> Result<Vec<String>, ConsoleError> {
+        let mut tokens = vec![];
+        for (i, word) in words.iter().enumerate() {
+            if i > 0 && !word.quoted && word.text == "--" {
+                tokens.push(word.text.clone());
+                tokens.extend(words[i + 1..].iter().map(|word| word.text.clone()));
+                break;
+            }
+
+            // The command name itself is never a response file
+            if self.response_files && i > 0 && !word.quoted {
+                if let Some(path) = word.text.strip_prefix('@').filter(|p| !p.is_empty()) {
+                    // The arguments from a response file are used as-is
+                    tokens.extend(expand::read_response_file(path, &self.current_dir)?);
+                    continue;
+                }
+            }
+
+            let text = expand::expand_tilde(word);
+
+            if !self.globbing || word.quoted || !expand::has_glob_chars(&text) {
+                tokens.push(text);
+                continue;
+            }
+
+            let matches = expand::glob(&text, &self.current_dir);
+            if !matches.is_empty() {
+                tokens.extend(matches);
+            } else if self.failglob {
+                return Err(ConsoleError::NoGlobMatchesError(text));
+            } else {
+                tokens.push(text);
+            }
+        }
+
+        Ok(tokens)
+    }
+
+    /// The prompt to show before the next line, after the status symbol if
+    /// it's enabled.
+    fn current_prompt(&self) -> std::borrow::Cow<'_, str> {
+        if !self.status_prompt {
+            return std::borrow::Cow::Borrowed(&self.prompt);
+        }
+
+        let symbol = match (self.last_status == 0, self.color_enabled()) {
+            (true, true) => "✓".green().to_string(),
+            (false, true) => "✗".red().to_string(),
+            (true, false) => "✓".to_string(),
+            (false, false) => "✗".to_string(),
+        };
+        std::borrow::Cow::Owned(format!("{symbol} {}", self.prompt))
+    }
+
+    /// Read one logical line, without adding it to the history, prompting
+    /// for more input with the continuation prompt for as long as the line
+    /// ends in an unescaped backslash or an open quote.
+    fn read_logical_line(&self, rl: &mut ConsoleEditor) -> Result<String, ReadlineError> {
+        let mut line = rl.readline(&self.current_prompt())?;
+        while prepare_continuation(&mut line, self.backslash()) {
+            line.push_str(&rl.readline(&self.continuation_prompt)?);
+        }
+
+        Ok(line)
+    }
+
+    /// Read one logical line like [`Console::read_logical_line`], or return
+    /// `None` if the idle timeout passed meanwhile and its callback ended the
+    /// read. If the read couldn't be ended, the loop ends after the line is
+    /// run instead.
+    fn read_before_idle_timeout(
+        &mut self,
+        rl: &mut ConsoleEditor,
+    ) -> Result<Option<String>, ReadlineError> {
+        let Some(mut idle) = self.idle_timeout.take() else {
+            return self.read_logical_line(rl).map(Some);
+        };
+
+        // A printer can only be created for a terminal
+        let mut printer = rl.create_external_printer().ok();
+        let stdin = printer.as_ref().and_then(|_| Stdin::save());
+        let (line, timed_out) = idle.watch(
+            || self.read_logical_line(rl),
+            || {
+                if let (Some(printer), Some(stdin)) = (&mut printer, &stdin) {
+                    stdin.end_read(printer);
+                }
+            },
+        );
+        if let Some(stdin) = stdin {
+            stdin.restore(timed_out);
+        }
+        self.idle_timeout = Some(idle);
+
+        match line {
+            Ok(line) if timed_out => {
+                self.exit_requested = true;
+                Ok(Some(line))
+            }
+            Err(_) if timed_out => Ok(None),
+            line => line.map(Some),
+        }
+    }
+
+    /// Run the external command `name`, passing it `stdin` and appending its
+    /// output to `stdout`. With `merge_stderr`, as after `2>&1`, its stderr is
+    /// part of its output; otherwise it goes straight to the console's.
+    fn run_external_command(
+        &self,
+        name: &str,
+        args: &Vec<&str>,
+        piped: bool,
+        merge_stderr: bool,
+        stdin: &str,
+        stdout: &mut String,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        /*
+         * There are a lot of `expect()`s here. Maybe at some point these can be
+         * handled, but for now they are outside the scope of an
+         * user-interactive application.
+         */
+
+        log::debug!("spawning external command `{name}` with arguments {args:?}");
+        let max_output_bytes = self.max_output_bytes;
+        let interrupt = InterruptGuard::new();
+        let mut command = if cfg!(windows) && CMD_BUILTINS.contains(&&*name.to_lowercase()) {
+            // These are built into `cmd.exe` rather than programs of their own
+            let mut command = std::process::Command::new("cmd");
+            command.arg("/C").arg(name);
+            command
+        } else {
+            std::process::Command::new(name)
+        };
+        command
+            .args(args)
+            .current_dir(&self.current_dir)
+            .stdin(Stdio::piped());
+        // Both streams write to the same pipe, so their output stays in order
+        let merged = if merge_stderr {
+            let (reader, writer) = std::io::pipe()?;
+            command.stdout(writer.try_clone()?).stderr(writer);
+            Some(reader)
+        } else {
+            command.stdout(Stdio::piped());
+            None
+        };
+        let spawned = command.spawn();
+        // The command holds the write end of a merged pipe, which has to be
+        // closed for the output to end when the child's does
+        drop(command);
+        let mut child = spawned.map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                ConsoleError::ExternalCommandNotFoundError(name.to_string())
+            }
+            _ => ConsoleError::ExternalSpawnError(name.to_string(), e.to_string()),
+        })?;
+
+        let mut child_stdin = child
+            .stdin
+            .take()
+            .expect("Could not acquire stdin for child process");
+        let child_stdout: Box<dyn std::io::Read + Send> = match merged {
+            Some(reader) => Box::new(reader),
+            None => Box::new(
+                child
+                    .stdout
+                    .take()
+                    .expect("Could not acquire stdout for child process"),
+            ),
+        };
+
+        // Write stdin on its own thread while this one reads the output, so a
+        // child that fills its stdout before it has read all of its stdin
+        // can't deadlock us
+        let output = std::thread::scope(|s| -> Result<_, Box<dyn std::error::Error>> {
+            let writer = s.spawn(move || match child_stdin.write_all(stdin.as_bytes()) {
+                // The child exited without reading all of its input, which is
+                // fine (e.g. `head`)
+                Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+                // Dropping `child_stdin` here closes it, signalling EOF
+                result => result,
+            });
+
+            // Reading one byte more than the limit shows it was exceeded
+            let limit = max_output_bytes.map_or(u64::MAX, |max| max as u64 + 1);
+            // The reader isn't scoped, so it can be left behind if the child is
+            // interrupted, since its own children may keep its stdout open
+            let reader = std::thread::spawn(move || {
+                let mut output = vec![];
+                let read = child_stdout.take(limit).read_to_end(&mut output);
+                (output, read)
+            });
+
+            // Ctrl-C normally ends the child, but one that handles it itself is
+            // killed, so that control returns to the console either way
+            while !reader.is_finished() {
+                if interrupt.interrupted() {
+                    log::debug!("killing `{name}`, which was interrupted");
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    let _ = writer.join();
+                    return Err(Box::new(ConsoleError::InterruptedError(name.to_string())));
+                }
+                std::thread::sleep(INTERRUPT_POLL_INTERVAL);
+            }
+            let (output, read) = reader
+                .join()
+                .expect("Panic while reading from child process");
+
+            let exceeded = max_output_bytes.filter(|&max| output.len() > max);
+            if exceeded.is_some() {
+                log::debug!("killing `{name}`, which exceeded the output limit");
+                let _ = child.kill();
+            }
+
+            let status = child.wait();
+            writer
+                .join()
+                .expect("Panic while writing to child process stdin")
+                .map_err(|e| format!("IO error writing to `{name}`: {e}"))?;
+            if let Some(max) = exceeded {
+                return Err(Box::new(ConsoleError::OutputLimitError(
+                    name.to_string(),
+                    max,
+                )));
+            }
+            if interrupt.interrupted() {
+                return Err(Box::new(ConsoleError::InterruptedError(name.to_string())));
+            }
+            read.and(status)
+                .map_err(|e| format!("IO error reading from `{name}`: {e}"))?;
+            Ok(output)
+        })?;
+
+        // Output that's only printed can be shown lossily, but passing it on
+        // lossily would silently corrupt it
+        let text = match String::from_utf8(output) {
+            Ok(text) => text,
+            Err(_) if piped => {
+                return Err(Box::new(ConsoleError::NonUtf8PipeDataError(
+                    name.to_string(),
+                )))
+            }
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        };
+        write!(stdout, "{text}").map_err(|e| format!("IO error {e}"))?;
+
+        Ok(())
+    }
+
+    /// Create a console with the settings in `config`, which can be changed
+    /// further with the `with_*` methods.
+    pub fn from_config(config: ConsoleConfig) -> Self {
+        let console = Self::default()
+            .with_prompt(config.prompt)
+            .with_color(config.color)
+            .with_edit_mode(config.edit_mode)
+            .with_externals(config.externals);
+        match config.history_file {
+            Some(path) => console.with_history_file(path),
+            None => console,
+        }
+    }
+
+    pub fn add_command(self, cmd: Box<dyn Command>) -> Self {
+        self.commands.borrow_mut().insert(cmd.get_name(), cmd);
+        self
+    }
+
+    /// Add each of `cmds` in turn, like [`Console::add_command`], so a later
+    /// command replaces an earlier one with the same name.
+    pub fn add_commands(self, cmds: impl IntoIterator<Item = Box<dyn Command>>) -> Self {
+        cmds.into_iter().fold(self, Console::add_command)
+    }
+
+    /// Like [`Console::add_command`], but first check that the command's
+    /// parser is well-formed, e.g. that no two of its arguments have the same
+    /// name or flag, rather than finding out when it's completed or run.
+    ///
+    /// The checks are clap's debug assertions, so they're only made in debug
+    /// builds. Their panic message is still printed when one fails.
+    pub fn try_add_command(self, cmd: Box<dyn Command>) -> Result<Self, ConsoleError> {
+        validate_command(cmd.as_ref())?;
+        Ok(self.add_command(cmd))
+    }
+
+    /// Replace every registered command with the result of `wrap`, e.g. to
+    /// add logging or authorization to all of them. Commands registered
+    /// afterwards aren't wrapped.
+    pub fn wrap_commands(self, mut wrap: impl FnMut(Box<dyn Command>) -> Box<dyn Command>) -> Self {
+        let commands = std::mem::take(&mut *self.commands.borrow_mut());
+        commands
+            .into_values()
+            .fold(self, |console, cmd| console.add_command(wrap(cmd)))
+    }
+
+    /// Register the ready-made commands in [`builtins`](crate::builtins):
+    /// `echo`, `cat`, `grep` and `head`.
+    #[cfg(feature = "builtins")]
+    pub fn with_builtins(self) -> Self {
+        crate::builtins::all()
+            .into_iter()
+            .fold(self, |console, cmd| console.add_command(cmd))
+    }
+
+    /// Apply `filter` to the output of every stage of a pipeline, internal or
+    /// external. It runs after each stage, before its output is passed on as
+    /// the next stage's stdin, so the output printed at the end of the pipeline
+    /// has been filtered exactly once, by the last stage.
+    pub fn with_output_filter(mut self, filter: Box<dyn Fn(String) -> String>) -> Self {
+        self.output_filter = Some(filter);
+        self
+    }
+
+    /// Answer the questions commands ask with [`ExecContext::confirm`] by
+    /// calling `handler` with the question, instead of asking on the terminal.
+    /// Useful for a GUI, or to confirm everything in scripts.
+    pub fn with_confirm_handler(mut self, handler: Box<dyn Fn(&str) -> bool>) -> Self {
+        self.confirm_handler = Some(handler);
+        self
+    }
+
+    /// Pass the output at the end of each pipeline run with
+    /// [`Console::run_line`] to `sink`, e.g. to page it or show it in a GUI,
+    /// instead of printing it to stdout. Live and streamed output is passed on
+    /// piece by piece as it's produced. Errors are still printed to stderr.
+    pub fn with_output_sink(mut self, sink: Box<dyn FnMut(&str)>) -> Self {
+        self.output_sink = Some(sink);
+        self
+    }
+
+    /// Pass the output at the end of each pipeline run with
+    /// [`Console::run_line`] through `transform` before printing it, e.g. to
+    /// add a missing trailing newline or highlight JSON. Unlike the output
+    /// filter, it runs once per line, on the output of the last stage only,
+    /// so it never affects what's piped. Live and streamed output, which is
+    /// printed as it's produced, isn't transformed.
+    pub fn with_final_output_transform(
+        mut self,
+        transform: Box<dyn FnMut(String) -> String>,
+    ) -> Self {
+        self.final_output_transform = Some(transform);
+        self
+    }
+
+    /// Expand unquoted arguments containing `*`, `?` or `[...]` into the
+    /// sorted list of paths they match, like a shell does. Disabled by default.
+    pub fn with_globbing(mut self, globbing: bool) -> Self {
+        self.globbing = globbing;
+        self
+    }
+
+    /// Replace any unquoted argument of the form `@path` with the arguments
+    /// read from the file at `path`, split using the usual quoting rules.
+    /// Disabled by default.
+    pub fn with_response_files(mut self, response_files: bool) -> Self {
+        self.response_files = response_files;
+        self
+    }
+
+    /// Choose what happens to a glob that matches nothing. By default it is
+    /// passed on literally; with `failglob` set, the command line is rejected
+    /// with an error instead, like bash's `failglob` option.
+    pub fn with_failglob(mut self, failglob: bool) -> Self {
+        self.failglob = failglob;
+        self
+    }
+
+    /// Call `hook` with the name of any unrecognized command. If it returns
+    /// `true` the command is considered handled and no error is printed. In
+    /// either case, the rest of the command line is not run.
+    pub fn with_command_not_found(mut self, hook: Box<dyn FnMut(&str) -> bool>) -> Self {
+        self.command_not_found = Some(hook);
+        self
+    }
+
+    /// Set how many edits away from an unrecognized command name a registered
+    /// command may be and still be suggested as a correction. Defaults to `2`;
+    /// `0` disables suggestions.
+    pub fn with_suggestion_threshold(mut self, threshold: usize) -> Self {
+        self.suggestion_threshold = threshold;
+        self
+    }
+
+    /// Set the prompt shown before each line. Defaults to `"> "`.
+    pub fn with_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.prompt = prompt.into();
+        self
+    }
+
+    /// Force colored output on or off, or with `None`, decide from the
+    /// environment as described in [`Console::color_enabled`], which is the
+    /// default.
+    pub fn with_color(mut self, color: Option<bool>) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Show whether the last line succeeded before the prompt, with a green
+    /// `✓` if its [status](Console::last_status) was `0` and a red `✗`
+    /// otherwise. Disabled by default.
+    pub fn with_status_prompt(mut self, status_prompt: bool) -> Self {
+        self.status_prompt = status_prompt;
+        self
+    }
+
+    /// Set the prompt shown while reading the continuation of a line that
+    /// ended in a backslash or an open quote. Defaults to `"... "`.
+    pub fn with_continuation_prompt(mut self, prompt: impl Into<String>) -> Self {
+        self.continuation_prompt = prompt.into();
+        self
+    }
+
+    /// Skip the lines typed at the prompt whose first character, other than
+    /// whitespace, is `comment_char`, e.g. to paste in commands annotated with
+    /// comments. They're still added to the history. Only whole lines are
+    /// skipped, and by default none are.
+    pub fn with_comment_char(mut self, comment_char: Option<char>) -> Self {
+        self.comment_char = comment_char;
+        self
+    }
+
+    /// Match command names, including those of the built-in commands,
+    /// regardless of case, so that e.g. `ECHO` runs `echo`. Aliases and
+    /// external commands are still case-sensitive. Disabled by default.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// Pass every line run to `rewriter` before it is parsed, and run the
+    /// line it returns instead, e.g. to implement custom syntax. It runs once
+    /// per line, after any continuation lines have been joined and after the
+    /// line has been added to the history, so the history holds the lines as
+    /// typed and rerunning one rewrites it again.
+    pub fn with_line_rewriter(mut self, rewriter: Box<dyn FnMut(String) -> String>) -> Self {
+        self.line_rewriter = Some(rewriter);
+        self
+    }
+
+    /// Choose how completions are offered: listed all at once, or cycled
+    /// through on each tab. Defaults to [`rustyline::CompletionType::List`].
+    pub fn with_completion_type(mut self, completion_type: rustyline::CompletionType) -> Self {
+        self.completion_type = completion_type;
+        self
+    }
+
+    /// Call `on_exit` when the interactive loop ends, whether at EOF, on
+    /// Ctrl-C or because of an error, e.g. to save state. It is only called
+    /// the first time the loop ends.
+    pub fn with_on_exit(mut self, on_exit: Box<dyn FnOnce()>) -> Self {
+        self.on_exit = Some(on_exit);
+        self
+    }
+
+    /// Call `callback` whenever `timeout` passes at the prompt without a key
+    /// being pressed, e.g. to end a session left unattended. If it returns
+    /// `true` the interactive loop ends, otherwise the console waits for
+    /// another `timeout`. The callback runs on its own thread, since the
+    /// prompt blocks the console's. Ending the loop interrupts the prompt on
+    /// Unix terminals; elsewhere it ends once the line being read is entered
+    /// and run.
+    pub fn with_idle_timeout(mut self, timeout: Duration, callback: Box<IdleCallback>) -> Self {
+        self.idle_timeout = Some(IdleTimeout::new(timeout, callback));
+        self
+    }
+
+    /// Choose between emacs and vi key bindings for editing lines. Defaults to
+    /// [`rustyline::EditMode::Emacs`].
+    pub fn with_edit_mode(mut self, edit_mode: rustyline::EditMode) -> Self {
+        self.edit_mode = edit_mode;
+        self
+    }
+
+    /// Choose whether lines entered at the prompt are added to the history,
+    /// and so to the history file. Enabled by default.
+    pub fn with_auto_add_history(mut self, add_history: bool) -> Self {
+        self.add_history = add_history;
+        self
+    }
+
+    /// Show the help text of each argument next to it when listing
+    /// completions, e.g. `[-n]  Do not append a newline`. Disabled by default.
+    pub fn with_completion_help(mut self, completion_help: bool) -> Self {
+        self.completion_help = completion_help;
+        self
+    }
+
+    /// Pass the candidates for each completion to `filter`, which can reorder
+    /// or remove them, e.g. to sort them by relevance, before they're shown.
+    pub fn with_completion_filter(mut self, filter: Box<CompletionFilter>) -> Self {
+        self.completion_filter = Some(filter.into());
+        self
+    }
+
+    /// Load the history from the file at `path` when the interactive loop
+    /// starts, and append each line entered to it.
+    pub fn with_history_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.history_file = Some(path.into());
+        self
+    }
+
+    /// Measure how long each stage of a pipeline takes to run, and print the
+    /// durations to stderr as e.g. `upper: 12ms` once the pipeline completes.
+    /// Disabled by default.
+    pub fn with_timing(mut self, timing: bool) -> Self {
+        self.timing = timing;
+        self
+    }
+
+    /// Print each line to stderr, prefixed with `+ `, before running it, like
+    /// a shell's `set -x`. Aliases are shown unexpanded, but command
+    /// substitutions have already been performed. Disabled by default.
+    pub fn with_trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Take backslashes in command lines literally, rather than as escapes, so
+    /// Windows paths like `C:\Users` can be typed as they are. Quotes are then
+    /// only quoted with the other kind of quote, and a trailing backslash
+    /// doesn't continue the line. Enabled by default on Windows.
+    pub fn with_windows_paths(mut self, windows_paths: bool) -> Self {
+        self.windows_paths = windows_paths;
+        self
+    }
+
+    /// Color the line as it's typed: command names are green if they're
+    /// recognized and red if not, and flags are cyan. Only takes effect when
+    /// the console emits colored output (see [`Console::color_enabled`]).
+    /// Disabled by default.
+    pub fn with_highlighting(mut self, highlighting: bool) -> Self {
+        self.highlighting = highlighting;
+        self
+    }
+
+    /// Allow external commands to be run with `!`. Enabled by default. When
+    /// disabled, e.g. for a console exposed to untrusted users, a line with an
+    /// external command is rejected before any of it runs, and external
+    /// commands aren't completed.
+    pub fn with_externals(mut self, externals: bool) -> Self {
+        self.externals = externals;
+        self
+    }
+
+    /// Only allow the external commands in `names` to be run, rejecting a line
+    /// with any other before any of it runs. Names are matched exactly as
+    /// typed after the `!`, so e.g. allowing `ls` doesn't allow `/bin/ls`. Has
+    /// no effect if external commands are disabled with
+    /// [`Console::with_externals`].
+    pub fn with_external_allowlist(mut self, names: HashSet<String>) -> Self {
+        self.external_allowlist = Some(names);
+        self
+    }
+
+    /// While typing, show the rest of the most recent history entry that
+    /// starts with the line so far as a dimmed hint. Pressing the right arrow
+    /// at the end of the line accepts it. Disabled by default.
+    pub fn with_history_hints(mut self, history_hints: bool) -> Self {
+        self.history_hints = history_hints;
+        self
+    }
+
+    /// Recognize ANSI-C quoted strings, written `$'...'`, in which escapes
+    /// like `\n`, `\t` and `\x41` stand for the characters they do in C, so
+    /// e.g. `$'a\tb'` is a tab-separated word. Disabled by default, in which
+    /// case `$` is literal.
+    pub fn with_ansi_c_quoting(mut self, ansi_c_quoting: bool) -> Self {
+        self.ansi_c_quoting = ansi_c_quoting;
+        self
+    }
+
+    /// Run commands that implement [`StreamingCommand`] on their own threads,
+    /// passing lines between the stages of a pipeline as they're produced.
+    /// The output of such a pipeline is printed line by line. Disabled by
+    /// default, in which case each stage runs to completion in turn. See
+    /// [`crate::streaming`].
+    pub fn with_streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    /// Run `lines` with [`Console::run_line`] when [`Console::cmd_loop`]
+    /// starts, before the first prompt, e.g. to define aliases. Errors are
+    /// reported and the next line runs, unless
+    /// [`Console::with_abort_on_startup_error`] is set.
+    pub fn with_startup_commands(mut self, lines: Vec<String>) -> Self {
+        self.startup_commands = lines;
+        self
+    }
+
+    /// End [`Console::cmd_loop`] with the failing line's status if a startup
+    /// command fails, instead of going on to the prompt. Disabled by default.
+    pub fn with_abort_on_startup_error(mut self, abort: bool) -> Self {
+        self.abort_on_startup_error = abort;
+        self
+    }
+
+    /// Stop any stage of a pipeline from producing more than `max` bytes of
+    /// output, which is otherwise held in memory until the next stage runs. An
+    /// external command that exceeds it is killed, and an internal one fails
+    /// to write any more. Either way, the line fails with
+    /// [`ConsoleError::OutputLimitError`]. There is no limit by default.
+    pub fn with_max_output_bytes(mut self, max: usize) -> Self {
+        self.max_output_bytes = Some(max);
+        self
+    }
+
+    /// Reject command lines with more than `max` pipeline stages before
+    /// running any of them. There is no limit by default.
+    pub fn with_max_pipeline_len(mut self, max: usize) -> Self {
+        self.max_pipeline_len = Some(max);
+        self
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        let current_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("."));
+        let completion_dir = Rc::new(RefCell::new(current_dir.clone()));
+        Self {
+            prompt: "> ".to_string(),
+            continuation_prompt: "... ".to_string(),
+            status_prompt: false,
+            comment_char: None,
+            commands: Rc::new(RefCell::new(HashMap::new())),
+            output_filter: None,
+            output_sink: None,
+            final_output_transform: None,
+            confirm_handler: None,
+            interactive: false,
+            globbing: false,
+            failglob: false,
+            command_not_found: None,
+            suggestion_threshold: 2,
+            last_status: 0,
+            response_files: false,
+            aliases: AliasSet::default(),
+            max_pipeline_len: None,
+            max_output_bytes: None,
+            timing: false,
+            trace: false,
+            windows_paths: cfg!(windows),
+            history_file: None,
+            case_insensitive: false,
+            completion_help: false,
+            line_rewriter: None,
+            completion_type: rustyline::CompletionType::List,
+            add_history: true,
+            edit_mode: rustyline::EditMode::Emacs,
+            on_exit: None,
+            idle_timeout: None,
+            outer_consoles: vec![],
+            pending_control: None,
+            exit_requested: false,
+            streaming: false,
+            highlighting: false,
+            history_hints: false,
+            color: None,
+            externals: true,
+            external_allowlist: None,
+            completion_filter: None,
+            current_dir,
+            completion_dir,
+            previous_dir: None,
+            vars: BTreeMap::new(),
+            completion_vars: Rc::default(),
+            ansi_c_quoting: false,
+            command_stats: HashMap::new(),
+            source_depth: 0,
+            repeating: false,
+            startup_commands: vec![],
+            abort_on_startup_error: false,
         }
     }
 }
@@ -311,3 +2889,1063 @@ impl From<ReadlineError> for ConsoleError {
         Self::ReadlineError(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestCommand;
+
+    impl Command for TestCommand {
+        fn get_name(&self) -> String {
+            "test".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("test")
+                .version("1.0.0")
+                .arg(clap::Arg::new("input").required(true).help("Input file"))
+                .arg(
+                    clap::Arg::new("verbose")
+                        .short('v')
+                        .long("verbose")
+                        .action(clap::ArgAction::SetTrue),
+                )
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            write!(ctx.stdout, "{}", ctx.stdin)?;
+            Ok(())
+        }
+    }
+
+    struct StopCommand;
+
+    impl Command for StopCommand {
+        fn get_name(&self) -> String {
+            "stop".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("stop")
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Err(Box::new(Control::StopPipeline))
+        }
+    }
+
+    #[test]
+    fn pipelines_are_split_with_spans() {
+        assert_eq!(
+            split_pipeline("a 'b|c' | d\\|e|", lexer::Backslash::Escape),
+            [(0..8, "a 'b|c' "), (9..14, " d\\|e"), (15..15, "")]
+        );
+    }
+
+    #[test]
+    fn stop_pipeline_is_not_an_error() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .add_command(Box::new(StopCommand));
+
+        // Every stage is still parsed before the pipeline runs
+        assert!(console.run_line("test input | stop | nope").is_err());
+        assert!(console.run_line("test input | stop | test input").is_ok());
+        assert_eq!(console.last_status(), 0);
+        assert_eq!(console.capture_line("stop | test input").unwrap(), "");
+    }
+
+    struct ConfigureCommand;
+
+    impl Command for ConfigureCommand {
+        fn get_name(&self) -> String {
+            "configure".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("configure")
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            let sub = SubConsole::new("(config)> ").add_command(Box::new(StopCommand));
+            Err(Box::new(Control::EnterSubConsole(sub)))
+        }
+    }
+
+    #[test]
+    fn sub_consoles_replace_commands_until_exit() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .add_command(Box::new(ConfigureCommand));
+
+        console.run_line("configure").unwrap();
+        assert_eq!(console.prompt, "(config)> ");
+        assert!(console.run_line("stop").is_ok());
+        assert!(console.run_line("test input").is_err());
+
+        console.run_line("exit").unwrap();
+        assert_eq!(console.prompt, "> ");
+        assert!(console.run_line("test input").is_ok());
+
+        // At the top level, `exit` ends a script
+        let script = "exit\ntest\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 0);
+    }
+
+    struct RawCommand;
+
+    impl Command for RawCommand {
+        fn get_name(&self) -> String {
+            "raw".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("raw")
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            unreachable!("raw commands are run with `execute_raw`")
+        }
+
+        fn long_help(&self) -> Option<String> {
+            Some("Examples:\n  raw 1 + 2\n".to_string())
+        }
+
+        fn wants_raw_args(&self) -> bool {
+            true
+        }
+
+        fn execute_raw(
+            &self,
+            args: &[String],
+            ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            write!(ctx.stdout, "{args:?}")?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn source_runs_scripts_in_the_session() {
+        let dir = crate::tests::TempDir::new("source");
+        let script = dir.join("setup.cmd3");
+        std::fs::write(&script, "# Set up\nalias t='test input'\nconfigure\n").unwrap();
+
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .add_command(Box::new(ConfigureCommand));
+        let line = format!("source {}", script.display());
+        console.run_line(&line).unwrap();
+        assert_eq!(console.aliases.borrow().get("t").unwrap(), "test input");
+        // The sub-console is entered once `source` finishes
+        assert_eq!(console.prompt, "(config)> ");
+
+        console.run_line("exit").unwrap();
+        assert!(console.run_line("source nope.cmd3").is_err());
+
+        let broken = dir.join("broken.cmd3");
+        std::fs::write(
+            &broken, "nope
+",
+        )
+        .unwrap();
+        let line = format!("source {}", broken.display());
+        assert!(console.run_line(&line).is_err());
+        assert_eq!(console.last_status(), 1);
+        // Only the last line counts
+        std::fs::write(
+            &broken,
+            "nope
+test input
+",
+        )
+        .unwrap();
+        console.run_line(&line).unwrap();
+        assert_eq!(console.last_status(), 0);
+    }
+
+    #[test]
+    fn output_is_limited() {
+        let mut console = Console::default().with_max_output_bytes(10);
+
+        assert_eq!(console.capture_line("!printf abc").unwrap(), "abc");
+        assert_eq!(
+            console.capture_line("!yes"),
+            Err(ConsoleError::OutputLimitError("yes".to_string(), 10))
+        );
+        assert_eq!(
+            console.capture_line("help"),
+            Err(ConsoleError::OutputLimitError("help".to_string(), 10))
+        );
+    }
+
+    /// Greet someone.
+    #[derive(clap::Parser)]
+    struct GreetArgs {
+        name: String,
+    }
+
+    #[test]
+    fn commands_can_be_defined_with_a_macro() {
+        let greet = crate::command!("greet", GreetArgs, |args, ctx| {
+            write!(ctx.stdout, "Hello, {}!", args.name)?;
+            Ok(())
+        });
+        let mut console = Console::default().add_command(Box::new(greet));
+
+        assert_eq!(console.capture_line("greet you").unwrap(), "Hello, you!");
+        assert!(console
+            .capture_line("help greet")
+            .unwrap()
+            .contains("Usage: greet"));
+    }
+
+    #[test]
+    fn commands_can_be_added_together() {
+        let cmds: Vec<Box<dyn Command>> = vec![
+            Box::new(TestCommand),
+            Box::new(StopCommand),
+            Box::new(TestCommand),
+        ];
+        let console = Console::default().add_commands(cmds);
+
+        assert_eq!(console.commands.borrow().len(), 2);
+        assert!(console.has_command("test"));
+    }
+
+    #[test]
+    fn lines_can_be_completed() {
+        let console = Console::default().add_command(Box::new(TestCommand));
+
+        let (start, pairs) = console.complete("pwd | te", 8).unwrap();
+        let replacements: Vec<_> = pairs.into_iter().map(|pair| pair.replacement).collect();
+        assert_eq!((start, replacements), (6, vec!["test".to_string()]));
+    }
+
+    #[test]
+    fn comment_lines_are_recognized() {
+        let console = Console::default().with_comment_char(Some(';'));
+
+        assert!(console.is_comment("; note"));
+        assert!(console.is_comment("  ;"));
+        assert!(!console.is_comment("echo ;"));
+        assert!(!console.is_comment(""));
+        assert!(!Console::default().is_comment("# note"));
+    }
+
+    #[test]
+    fn secret_commands_are_recognized() {
+        struct LoginCommand;
+
+        impl Command for LoginCommand {
+            fn get_name(&self) -> String {
+                "login".to_string()
+            }
+
+            fn get_parser(&self) -> clap::Command {
+                clap::Command::new("login").arg(clap::Arg::new("password").long("password"))
+            }
+
+            fn execute(
+                &self,
+                _args: clap::ArgMatches,
+                _ctx: &mut ExecContext<'_>,
+            ) -> Result<(), Box<dyn std::error::Error>> {
+                Ok(())
+            }
+
+            fn secret(&self) -> bool {
+                true
+            }
+        }
+
+        let mut console = Console::default()
+            .add_command(Box::new(LoginCommand))
+            .add_command(Box::new(TestCommand));
+
+        assert!(console.is_secret("login --password hunter2"));
+        assert!(console.is_secret("test x | login --password x"));
+        assert!(console.is_secret("repeat 2 login --password x"));
+        assert!(console.is_secret("test $(test `login --password x`)"));
+        console.run_line("alias l=login").unwrap();
+        assert!(console.is_secret("l --password x"));
+        assert!(console.is_secret("  'login' --password 'x y'"));
+        assert!(!console.is_secret("test login"));
+        assert!(!console.is_secret(""));
+    }
+
+    #[test]
+    fn stderr_can_be_merged_into_the_output() {
+        let mut console = Console::default();
+
+        assert_eq!(
+            console
+                .capture_line("!sh -c 'echo out; echo err >&2; echo more' 2>&1 | !cat")
+                .unwrap(),
+            "out\nerr\nmore\n"
+        );
+        // Quoted, it's an ordinary argument
+        assert_eq!(console.capture_line("!echo '2>&1'").unwrap(), "2>&1\n");
+        assert_eq!(
+            console.dry_run_line("!ls 2>&1").unwrap(),
+            ["external command `ls` with arguments [], with stderr in its output"]
+        );
+    }
+
+    #[test]
+    fn commands_can_be_added_for_a_scope() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        let scoped = console.with_scoped_commands(vec![Box::new(StopCommand)], |console| {
+            console.has_command("stop")
+        });
+        assert!(scoped);
+        assert!(!console.has_command("stop"));
+        assert!(console.has_command("test"));
+
+        // Replaced commands come back, even after a panic
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let cmds: Vec<Box<dyn Command>> = vec![Box::new(TestCommand), Box::new(StopCommand)];
+            console.with_scoped_commands(cmds, |_| panic!("oops"))
+        }));
+        assert!(result.is_err());
+        assert!(console.has_command("test"));
+        assert!(!console.has_command("stop"));
+
+        // Entering a sub-console doesn't keep them in the outer console
+        console.with_scoped_commands(vec![Box::new(ConfigureCommand)], |console| {
+            console.run_line("configure").unwrap();
+        });
+        assert!(console.has_command("stop"));
+        console.run_line("exit").unwrap();
+        assert!(!console.has_command("configure"));
+        assert!(console.has_command("test"));
+    }
+
+    #[test]
+    fn prompts_can_show_the_last_status() {
+        let mut console = Console::default()
+            .with_color(Some(false))
+            .with_status_prompt(true);
+
+        assert_eq!(console.current_prompt(), "✓ > ");
+        assert!(console.run_line("nonexistent").is_err());
+        assert_eq!(console.current_prompt(), "✗ > ");
+        assert_eq!(Console::default().current_prompt(), "> ");
+    }
+
+    #[test]
+    fn expansions_end_at_double_dash() {
+        let mut console = Console::default().with_response_files(true);
+
+        assert!(console.capture_line("!echo @missing").is_err());
+        assert_eq!(
+            console.capture_line("!echo -- @missing ~").unwrap(),
+            "-- @missing ~\n"
+        );
+    }
+
+    #[test]
+    fn backslashes_can_be_literal() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let sink = output.clone();
+        let mut console = Console::default()
+            .with_windows_paths(true)
+            .with_output_sink(Box::new(move |s| sink.borrow_mut().push_str(s)));
+
+        assert_eq!(
+            console
+                .capture_line(r#"!printf '%s\n' C:\Users "C:\Program Files\""#)
+                .unwrap(),
+            "C:\\Users\nC:\\Program Files\\\n"
+        );
+        // Nor does one before a closing quote, so the quote ends before a `|`,
+        // a substitution's `)` or a redirection
+        assert_eq!(
+            console
+                .capture_line(r#"!echo "C:\x\" | !tr a-z A-Z"#)
+                .unwrap(),
+            "C:\\X\\\n"
+        );
+        assert_eq!(
+            console
+                .capture_line(r#"!echo "$(!echo '"C:\x\"')" $(!echo "it's")"#)
+                .unwrap(),
+            "\"C:\\x\\\" it's\n"
+        );
+        console
+            .run_line(&format!(r#"!echo "C:\x\" > {NULL_DEVICE}"#))
+            .unwrap();
+        assert_eq!(*output.borrow(), "");
+
+        // A trailing backslash doesn't continue the line
+        let script = "!printf '%s\\n' C:\\\n!printf done\n";
+        console.run_script(script.as_bytes()).unwrap();
+        assert_eq!(*output.borrow(), "C:\\\ndone");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn cmd_builtins_can_be_run() {
+        let mut console = Console::default();
+
+        assert!(console.capture_line("!dir").is_ok());
+        assert_eq!(console.capture_line("!echo hi").unwrap(), "hi\r\n");
+    }
+
+    #[test]
+    fn repeat_concatenates_output() {
+        let mut console = Console::default();
+
+        assert_eq!(
+            console
+                .capture_line(r"repeat 2 !printf '%s\n' 'a b'")
+                .unwrap(),
+            "a b\na b\n"
+        );
+        assert!(console.capture_line("repeat 2 repeat 2 pwd").is_err());
+        assert!(matches!(
+            console.run_line("repeat 20000 pwd"),
+            Err(ConsoleError::InvalidArgumentsError(_))
+        ));
+        assert_eq!(console.last_status(), 2);
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .add_command(Box::new(RawCommand));
+
+        assert!(console.run_line("   test input   ").is_ok());
+        assert!(console.run_line("\ttest input |  test input\t").is_ok());
+        assert_eq!(console.capture_line("  raw  a  ").unwrap(), r#"["a"]"#);
+        assert_eq!(
+            console.dry_run_line("  which   test ").unwrap(),
+            [r#"built-in command `which` with name=["test"]"#]
+        );
+    }
+
+    /// Uppercases the output of the command it wraps.
+    struct Shout(Box<dyn Command>);
+
+    impl Command for Shout {
+        fn get_name(&self) -> String {
+            self.0.get_name()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            self.0.get_parser()
+        }
+
+        fn execute(
+            &self,
+            args: clap::ArgMatches,
+            ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            let mut output = String::new();
+            self.0.execute(args, &mut ctx.with_stdout(&mut output))?;
+            write!(ctx.stdout, "{}", output.to_uppercase())?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn commands_can_be_listed_and_wrapped() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .add_command(Box::new(StopCommand))
+            .wrap_commands(|cmd| Box::new(Shout(cmd)));
+
+        let commands = console.commands();
+        let names: Vec<&str> = commands.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["stop", "test"]);
+        assert!(commands.get("test").is_some());
+        drop(commands);
+
+        let output = console.capture_line("help test | test x").unwrap();
+        assert!(output.contains("USAGE: TEST"));
+    }
+
+    struct BrokenCommand;
+
+    impl Command for BrokenCommand {
+        fn get_name(&self) -> String {
+            "broken".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("broken")
+                .arg(clap::Arg::new("all").short('a'))
+                .arg(clap::Arg::new("append").short('a'))
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn malformed_parsers_are_rejected() {
+        assert!(Console::default()
+            .try_add_command(Box::new(TestCommand))
+            .is_ok());
+        assert!(matches!(
+            Console::default().try_add_command(Box::new(BrokenCommand)),
+            Err(ConsoleError::InvalidCommandError(name, _)) if name == "broken"
+        ));
+    }
+
+    #[test]
+    fn custom_editors_are_prepared() {
+        let console = Console::default();
+        let config = rustyline::Config::builder().auto_add_history(true).build();
+        let mut rl = ConsoleEditor::with_config(config).unwrap();
+        console.prepare_editor(&mut rl).unwrap();
+
+        assert!(rl.helper().is_some());
+        assert!(!rustyline::config::Configurer::config_mut(&mut rl).auto_add_history());
+    }
+
+    #[test]
+    fn has_command_follows_aliases() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .with_case_insensitive(true);
+        console.run_line("alias t='test -v' p=pwd").unwrap();
+
+        assert!(console.has_command("test"));
+        assert!(console.has_command("TEST"));
+        assert!(console.has_command("t"));
+        assert!(!console.has_command("p"));
+        assert!(!console.has_command("nope"));
+    }
+
+    struct ProgressCommand;
+
+    impl Command for ProgressCommand {
+        fn get_name(&self) -> String {
+            "progress".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("progress")
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            writeln!(ctx.stdout, "50%")?;
+            writeln!(ctx.stdout, "100%")?;
+            Ok(())
+        }
+
+        fn live_output(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn output_can_be_sent_to_a_sink() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let sink = output.clone();
+        let mut console = Console::default()
+            .add_command(Box::new(ProgressCommand))
+            .with_output_sink(Box::new(move |s| sink.borrow_mut().push_str(s)));
+
+        console.run_line("progress").unwrap();
+        console.run_line("progress | help progress").unwrap();
+        assert!(output.borrow().starts_with("50%\n100%\nUsage: progress"));
+    }
+
+    #[test]
+    fn output_can_be_discarded() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let sink = output.clone();
+        let mut console = Console::default()
+            .add_command(Box::new(ProgressCommand))
+            .with_output_sink(Box::new(move |s| sink.borrow_mut().push_str(s)));
+
+        console.run_line("progress > /dev/null").unwrap();
+        console
+            .run_line("progress | help progress >/dev/null")
+            .unwrap();
+        assert_eq!(*output.borrow(), "");
+        assert!(console.run_line("help nope > /dev/null").is_err());
+
+        console.run_line("!echo '> /dev/null'").unwrap();
+        assert_eq!(*output.borrow(), "> /dev/null\n");
+    }
+
+    #[test]
+    fn final_output_can_be_transformed() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let sink = output.clone();
+        let mut console =
+            Console::default()
+                .add_command(Box::new(ProgressCommand))
+                .with_output_sink(Box::new(move |s| sink.borrow_mut().push_str(s)))
+                .with_final_output_transform(Box::new(|s| {
+                    if s.ends_with('\n') {
+                        s
+                    } else {
+                        s + "\n"
+                    }
+                }));
+
+        console.run_line("!printf a").unwrap();
+        // What's piped isn't transformed, so `wc` only counts one newline
+        console.run_line(r"!printf 'a\nb' | !wc -l").unwrap();
+        // Nor is live output, which has no newline to add after it
+        console.run_line("progress").unwrap();
+        assert_eq!(*output.borrow(), "a\n1\n50%\n100%\n");
+    }
+
+    /// Asks before "removing" each of its arguments.
+    struct RemoveCommand;
+
+    impl Command for RemoveCommand {
+        fn get_name(&self) -> String {
+            "rm".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("rm").arg(clap::Arg::new("file").num_args(1..))
+        }
+
+        fn execute(
+            &self,
+            args: clap::ArgMatches,
+            ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn std::error::Error>> {
+            for file in args.get_many::<String>("file").into_iter().flatten() {
+                if ctx.confirm(&format!("Remove {file}?")) {
+                    writeln!(ctx.stdout, "removed {file}")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn confirmations_are_denied_unless_handled() {
+        let mut console = Console::default().add_command(Box::new(RemoveCommand));
+        assert_eq!(console.capture_line("rm a b").unwrap(), "");
+
+        let mut console = Console::default()
+            .add_command(Box::new(RemoveCommand))
+            .with_confirm_handler(Box::new(|message| message.contains('b')));
+        assert_eq!(console.capture_line("rm a b").unwrap(), "removed b\n");
+    }
+
+    /// Print a session variable, or set it to `value`.
+    #[derive(clap::Parser)]
+    struct VarArgs {
+        name: String,
+        value: Option<String>,
+    }
+
+    #[test]
+    fn commands_can_read_and_change_session_variables() {
+        let var = crate::command!("var", VarArgs, |args, ctx| {
+            match args.value {
+                Some(value) => {
+                    ctx.vars.insert(args.name, value);
+                }
+                None => write!(ctx.stdout, "{}", ctx.vars[&args.name])?,
+            }
+            Ok(())
+        });
+        let mut console = Console::default().add_command(Box::new(var));
+        console.set_var("user", "me");
+
+        assert_eq!(console.capture_line("var user").unwrap(), "me");
+        console.run_line("var user you").unwrap();
+        assert_eq!(console.var("user"), Some("you"));
+        assert_eq!(console.var("host"), None);
+
+        // Including to the completer
+        let (_, pairs) = console.complete("var $u", 6).unwrap();
+        assert_eq!(pairs[0].replacement, "$user");
+    }
+
+    #[test]
+    fn live_output_is_buffered_unless_printed() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .add_command(Box::new(ProgressCommand));
+
+        assert!(console.run_line("progress").is_ok());
+        assert_eq!(console.capture_line("progress").unwrap(), "50%\n100%\n");
+        assert_eq!(
+            console.capture_line("progress | test x").unwrap(),
+            "50%\n100%\n"
+        );
+    }
+
+    #[test]
+    fn cd_changes_the_directory_of_external_commands() {
+        let dir = crate::tests::TempDir::new("cd");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let mut console = Console::default();
+        let start = console.current_dir().to_path_buf();
+
+        console.run_line(&format!("cd {}", dir.display())).unwrap();
+        console.run_line("cd sub").unwrap();
+        assert_eq!(console.current_dir(), dir.join("sub"));
+        assert_eq!(
+            console.capture_line("!pwd").unwrap(),
+            format!("{}\n", dir.join("sub").display())
+        );
+        assert_eq!(
+            console.capture_line("pwd").unwrap(),
+            console.capture_line("!pwd").unwrap()
+        );
+        assert!(console.run_line("cd nope").is_err());
+        assert_eq!(console.current_dir(), dir.join("sub"));
+
+        console.run_line("cd -").unwrap();
+        assert_eq!(console.current_dir(), dir);
+        assert_ne!(console.current_dir(), start);
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_current_dir() {
+        let dir = crate::tests::TempDir::new("paths");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("top.txt"), "").unwrap();
+        std::fs::write(dir.join("sub/args.txt"), "a 'b c'\n").unwrap();
+        let mut console = Console::default()
+            .with_globbing(true)
+            .with_response_files(true);
+
+        console.run_line(&format!("cd {}", dir.display())).unwrap();
+        console.run_line("cd sub").unwrap();
+        assert_eq!(console.capture_line("!echo *.txt").unwrap(), "args.txt\n");
+        assert_eq!(
+            console.capture_line("!echo ../*.txt").unwrap(),
+            "../top.txt\n"
+        );
+        assert_eq!(console.capture_line("!echo @args.txt").unwrap(), "a b c\n");
+        let (start, pairs) = console.complete("source a", 8).unwrap();
+        assert_eq!((start, pairs[0].replacement.as_str()), (7, "args.txt"));
+        #[cfg(feature = "builtins")]
+        {
+            let mut console = console.with_builtins();
+            assert_eq!(console.capture_line("cat args.txt").unwrap(), "a 'b c'\n");
+        }
+    }
+
+    #[test]
+    fn externals_can_be_disabled() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .with_externals(false);
+
+        assert_eq!(
+            console.dry_run_line("test x | !wc -l"),
+            Err(ConsoleError::ExternalCommandsDisabledError(
+                "wc".to_string()
+            ))
+        );
+        assert!(console.capture_line("! true").is_err());
+        assert!(console.capture_line("echo $(!true)").is_err());
+
+        let allowlist = HashSet::from(["true".to_string()]);
+        let mut console = Console::default().with_external_allowlist(allowlist);
+        assert!(console.capture_line("!true").is_ok());
+        assert_eq!(
+            console.dry_run_line("!true | !false"),
+            Err(ConsoleError::ExternalCommandNotAllowedError(
+                "false".to_string()
+            ))
+        );
+        let mut console = console.with_externals(false);
+        assert!(matches!(
+            console.capture_line("!true"),
+            Err(ConsoleError::ExternalCommandsDisabledError(_))
+        ));
+    }
+
+    #[test]
+    fn config_sets_everything_at_once() {
+        let mut console = Console::from_config(ConsoleConfig {
+            prompt: "$ ".to_string(),
+            color: Some(false),
+            externals: false,
+            ..Default::default()
+        });
+
+        assert!(!console.color_enabled());
+        assert!(console.capture_line("!true").is_err());
+        assert_eq!(
+            console.capture_line("config").unwrap(),
+            "prompt: \"$ \"\nhistory file: none\ncolor: off\ncommands: 0\n"
+        );
+    }
+
+    #[test]
+    fn non_utf8_output_is_not_passed_on() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        assert_eq!(
+            console.capture_line(r"!printf '\377' | test x"),
+            Err(ConsoleError::BrokenPipeError(
+                1,
+                "printf".to_string(),
+                Box::new(ConsoleError::NonUtf8PipeDataError("printf".to_string()))
+            ))
+        );
+        // Output that's only printed is converted lossily
+        assert_eq!(console.capture_line(r"!printf '\377'").unwrap(), "\u{fffd}");
+    }
+
+    #[test]
+    fn counts_commands_run() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        console.run_line("test x | test x").unwrap();
+        // A command whose arguments can't be parsed never runs
+        assert!(console.run_line("test").is_err());
+        console.run_line("pwd").unwrap();
+        assert_eq!(
+            console.command_stats(),
+            HashMap::from([("test".to_string(), 2), ("pwd".to_string(), 1)])
+        );
+        assert_eq!(console.capture_line("stats").unwrap(), "pwd: 1\ntest: 2\n");
+    }
+
+    #[test]
+    fn ansi_c_quotes_are_interpreted_if_enabled() {
+        let mut console = Console::default()
+            .add_command(Box::new(RawCommand))
+            .with_ansi_c_quoting(true);
+        assert_eq!(
+            console.dry_run_line(r"raw $'a\tb|c' $'it\'s'").unwrap(),
+            [r#"command `raw` with raw arguments ["a\tb|c", "it's"]"#]
+        );
+
+        let mut console = Console::default().add_command(Box::new(RawCommand));
+        assert_eq!(
+            console.dry_run_line(r"raw $'a\tb'").unwrap(),
+            [r#"command `raw` with raw arguments ["$a\\tb"]"#]
+        );
+    }
+
+    #[test]
+    fn help_includes_long_help() {
+        let mut console = Console::default().add_command(Box::new(RawCommand));
+
+        let help = console.capture_line("help raw").unwrap();
+        assert!(help.starts_with("Usage: raw"));
+        assert!(help.ends_with("\nExamples:\n  raw 1 + 2\n"));
+    }
+
+    #[test]
+    fn raw_commands_skip_parsing() {
+        let mut console = Console::default().add_command(Box::new(RawCommand));
+
+        assert_eq!(
+            console.capture_line("raw 2 * (3 - -1) 'a b' ~").unwrap(),
+            r#"["2", "*", "(3", "-", "-1)", "a b", "~"]"#
+        );
+    }
+
+    #[test]
+    fn dry_run_describes_stages() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        assert_eq!(
+            console.dry_run_line("test -v input | !wc -l").unwrap(),
+            [
+                r#"command `test` with input=["input"], verbose=["true"]"#,
+                r#"external command `wc` with arguments ["-l"]"#,
+            ]
+        );
+        assert!(matches!(
+            console.dry_run_line("test"),
+            Err(ConsoleError::InvalidArgumentsError(_))
+        ));
+        assert_eq!(
+            console.dry_run_line("nope"),
+            Err(ConsoleError::UnrecognizedCommand("nope".to_string()))
+        );
+        assert_eq!(
+            console
+                .dry_run_line(&format!("test input > {NULL_DEVICE}"))
+                .unwrap(),
+            [r#"command `test` with input=["input"]"#, "output discarded"]
+        );
+    }
+
+    #[test]
+    fn help_and_version_are_output() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        assert_eq!(
+            console.capture_line("test --version").unwrap(),
+            "test 1.0.0\n"
+        );
+        let help = console.capture_line("test --help | test input").unwrap();
+        assert!(help.starts_with("Usage: test"), "{help}");
+        console.run_line("test -V").unwrap();
+        assert_eq!(console.last_status(), 0);
+    }
+
+    #[test]
+    fn line_rewriter_runs_before_parsing() {
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .with_line_rewriter(Box::new(|line| line.replace("@t", "test")));
+
+        assert!(console.run_line("@t input | @t input").is_ok());
+        assert!(console.run_line("nope").is_err());
+        assert_eq!(
+            console.dry_run_line("@t input").unwrap(),
+            ["command `test` with input=[\"input\"]"]
+        );
+    }
+
+    #[test]
+    fn errors_are_comparable() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        assert_eq!(
+            console.run_line("test input | !nope"),
+            Err(ConsoleError::BrokenPipeError(
+                2,
+                "nope".to_string(),
+                Box::new(ConsoleError::ExternalCommandNotFoundError(
+                    "nope".to_string()
+                ))
+            ))
+        );
+        assert_ne!(
+            ConsoleError::LexingError("a".to_string()),
+            ConsoleError::NoGlobMatchesError("a".to_string())
+        );
+        assert_eq!(
+            ConsoleError::ReadlineError(ReadlineError::Eof),
+            ConsoleError::ReadlineError(ReadlineError::Eof)
+        );
+    }
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(levenshtein("upper", "upper"), 0);
+        assert_eq!(levenshtein("uper", "upper"), 1);
+        assert_eq!(levenshtein("ehco", "echo"), 2);
+        assert_eq!(levenshtein("", "abc"), 3);
+    }
+
+    #[test]
+    fn run_script_returns_last_status() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        let script = "# comment\n\ntest \\\n  input\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 0);
+
+        let script = "test input\nnope\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 1);
+
+        let script = "test --bogus\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 2);
+    }
+
+    #[test]
+    fn aliases_expand_recursively() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+
+        let script = "alias t='test input' tt=t\ntt\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 0);
+
+        // A cycle stops at the first repeated alias, which isn't a command
+        let script = "alias a=b b=a\na\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 1);
+
+        let script = "unalias tt\ntt\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 1);
+    }
+
+    #[test]
+    fn startup_commands_run_until_aborted() {
+        let lines = vec!["alias t='test input'".to_string(), "nope".to_string()];
+        let mut console = Console::default()
+            .add_command(Box::new(TestCommand))
+            .with_startup_commands(lines.clone());
+        assert_eq!(console.run_startup_commands().unwrap(), None);
+        assert!(console.run_line("t").is_ok());
+
+        let mut console = Console::default()
+            .with_startup_commands(lines)
+            .with_abort_on_startup_error(true);
+        assert_eq!(console.run_startup_commands().unwrap(), Some(1));
+    }
+
+    #[test]
+    fn case_insensitive_commands() {
+        let mut console = Console::default().add_command(Box::new(TestCommand));
+        assert_eq!(console.run_script("TEST input\n".as_bytes()).unwrap(), 1);
+
+        let mut console = console.with_case_insensitive(true);
+        assert_eq!(console.run_script("TEST input\n".as_bytes()).unwrap(), 0);
+        assert_eq!(console.run_script("Which test\n".as_bytes()).unwrap(), 0);
+
+        // Aliases are still case-sensitive
+        let script = "alias t='test input'\nT\n";
+        assert_eq!(console.run_script(script.as_bytes()).unwrap(), 1);
+    }
+
+    #[test]
+    fn command_args_describes_parser() {
+        let console = Console::default().add_command(Box::new(TestCommand));
+
+        assert_eq!(
+            console.command_args("test"),
+            Some(vec![
+                ArgInfo {
+                    name: "input".to_string(),
+                    short: None,
+                    long: None,
+                    help: Some("Input file".to_string()),
+                    required: true,
+                },
+                ArgInfo {
+                    name: "verbose".to_string(),
+                    short: Some('v'),
+                    long: Some("verbose".to_string()),
+                    help: None,
+                    required: false,
+                },
+            ])
+        );
+        assert!(console.command_usage("test").is_some());
+        assert_eq!(console.command_args("missing"), None);
+        assert_eq!(console.command_usage("missing"), None);
+    }
+}
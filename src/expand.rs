@@ -0,0 +1,487 @@
+use std::path::{Path, PathBuf};
+
+use crate::{
+    console::ConsoleError,
+    lexer::{self, Backslash, Word},
+};
+
+/// Expand a leading `~` or `~user` in an unquoted word to the corresponding
+/// home directory. Words that are quoted, or whose home directory can't be
+/// determined, are left unchanged.
+pub(crate) fn expand_tilde(word: &Word) -> String {
+    if word.quoted {
+        return word.text.clone();
+    }
+
+    let Some(rest) = word.text.strip_prefix('~') else {
+        return word.text.clone();
+    };
+
+    let (user, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+
+    let home = if user.is_empty() {
+        std::env::home_dir()
+    } else {
+        user_home_dir(user)
+    };
+
+    match home {
+        Some(home) => format!("{}{path}", home.display()),
+        None => word.text.clone(),
+    }
+}
+
+/// Look up the home directory of `user` in `/etc/passwd`. Users only known to
+/// other name services (e.g. LDAP) are not found.
+#[cfg(unix)]
+fn user_home_dir(user: &str) -> Option<PathBuf> {
+    let passwd = std::fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines().find_map(|entry| {
+        let fields: Vec<&str> = entry.split(':').collect();
+        (fields.len() >= 6 && fields[0] == user).then(|| PathBuf::from(fields[5]))
+    })
+}
+
+#[cfg(not(unix))]
+fn user_home_dir(_user: &str) -> Option<PathBuf> {
+    None
+}
+
+/// Read the response file at `path`, relative to `current_dir`, and split its
+/// contents into arguments, using the same quoting rules as the command line.
+pub(crate) fn read_response_file(
+    path: &str,
+    current_dir: &Path,
+) -> Result<Vec<String>, ConsoleError> {
+    let contents = std::fs::read_to_string(current_dir.join(path))
+        .map_err(|e| ConsoleError::ResponseFileError(path.to_string(), e.to_string()))?;
+
+    let words = lexer::split(&contents).ok_or_else(|| {
+        ConsoleError::ResponseFileError(path.to_string(), "unterminated quote".to_string())
+    })?;
+
+    Ok(words.into_iter().map(|w| w.text).collect())
+}
+
+/// Replace each command substitution in `line`, written `$(...)` or
+/// `` `...` ``, with the output of running its contents with `run`. Trailing
+/// newlines are removed from the output, which is then quoted so that it is
+/// taken literally: inside double quotes it forms part of that word, otherwise
+/// it is split into words at whitespace. Substitutions may be nested, and
+/// aren't performed inside single quotes or comments. Backslashes are lexed as
+/// `backslash` says.
+pub(crate) fn substitute_commands(
+    line: &str,
+    backslash: Backslash,
+    run: &mut dyn FnMut(&str) -> Result<String, ConsoleError>,
+) -> Result<String, ConsoleError> {
+    let unterminated = || ConsoleError::LexingError(line.to_string());
+
+    let mut out = String::new();
+    let mut in_double_quotes = false;
+    let mut i = 0;
+    while let Some(ch) = line[i..].chars().next() {
+        let mut next = i + ch.len_utf8();
+        match ch {
+            '\\' if backslash == Backslash::Escape => {
+                next += line[next..].chars().next().map_or(0, char::len_utf8);
+                out.push_str(&line[i..next]);
+            }
+            '\'' if !in_double_quotes => {
+                next += line[next..].find('\'').ok_or_else(unterminated)? + 1;
+                out.push_str(&line[i..next]);
+            }
+            '"' => {
+                in_double_quotes = !in_double_quotes;
+                out.push(ch);
+            }
+            // A comment, as long as it starts a word
+            '#' if !in_double_quotes && (out.is_empty() || out.ends_with([' ', '\t', '\n'])) => {
+                out.push_str(&line[i..]);
+                break;
+            }
+            '$' if line[next..].starts_with('(') => {
+                let start = next + 1;
+                let len = matching_paren(&line[start..], backslash).ok_or_else(unterminated)?;
+                next = start + len + 1;
+                let output = run(&line[start..start + len])?;
+                push_substitution(&mut out, &output, in_double_quotes, backslash);
+            }
+            '`' => {
+                let len = closing_backtick(&line[next..], backslash).ok_or_else(unterminated)?;
+                let command = match backslash {
+                    Backslash::Escape => line[next..next + len].replace("\\`", "`"),
+                    Backslash::Literal => line[next..next + len].to_string(),
+                };
+                next += len + 1;
+                let output = run(&command)?;
+                push_substitution(&mut out, &output, in_double_quotes, backslash);
+            }
+            _ => out.push(ch),
+        }
+        i = next;
+    }
+
+    Ok(out)
+}
+
+/// The index of the `)` closing a `$(` whose contents start `text`, skipping
+/// over quoted text and nested parentheses.
+fn matching_paren(text: &str, backslash: Backslash) -> Option<usize> {
+    let mut depth = 0;
+    let mut in_double_quotes = false;
+    let mut chars = text.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' if backslash == Backslash::Escape => {
+                chars.next();
+            }
+            '\'' if !in_double_quotes => {
+                chars.find(|&(_, c)| c == '\'')?;
+            }
+            '"' => in_double_quotes = !in_double_quotes,
+            '(' if !in_double_quotes => depth += 1,
+            ')' if !in_double_quotes => {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// The index of the first backtick in `text` that isn't escaped.
+fn closing_backtick(text: &str, backslash: Backslash) -> Option<usize> {
+    let mut chars = text.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' if backslash == Backslash::Escape => {
+                chars.next();
+            }
+            '`' => return Some(i),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Append the `output` of a command substitution to the line being built in
+/// `out`, quoted so it's taken literally. With [`Backslash::Literal`], quotes
+/// can't be escaped, so they're quoted with the other kind instead.
+fn push_substitution(out: &mut String, output: &str, in_double_quotes: bool, backslash: Backslash) {
+    let output = output.trim_end_matches('\n');
+    if in_double_quotes {
+        for ch in output.chars() {
+            match (backslash, ch) {
+                (Backslash::Escape, '$' | '`' | '"' | '\\') => {
+                    out.push('\\');
+                    out.push(ch);
+                }
+                (Backslash::Literal, '"') => out.push_str(r#""'"'""#),
+                _ => out.push(ch),
+            }
+        }
+    } else {
+        let words: Vec<String> = output
+            .split_whitespace()
+            .map(|word| single_quote(word, backslash))
+            .collect();
+        out.push_str(&words.join(" "));
+    }
+}
+
+/// Quote `text` in single quotes, so that it forms one literal word. A single
+/// quote in it is escaped, or double-quoted if backslashes are literal.
+pub(crate) fn single_quote(text: &str, backslash: Backslash) -> String {
+    match backslash {
+        Backslash::Escape => format!("'{}'", text.replace('\'', r"'\''")),
+        Backslash::Literal => format!("'{}'", text.replace('\'', r#"'"'"'"#)),
+    }
+}
+
+/// Replace each ANSI-C quoted string in `line`, written `$'...'`, with an
+/// ordinary single-quoted string of the text it stands for, so that e.g.
+/// `$'a\tb'` becomes a tab-separated word. An unterminated `$'` is left as
+/// it is. Outside them, backslashes are lexed as `backslash` says.
+pub(crate) fn ansi_c_quotes(line: &str, backslash: Backslash) -> String {
+    let mut out = String::new();
+    let mut in_double_quotes = false;
+    let mut i = 0;
+    while let Some(ch) = line[i..].chars().next() {
+        let mut next = i + ch.len_utf8();
+        match ch {
+            '\\' if backslash == Backslash::Escape => {
+                next += line[next..].chars().next().map_or(0, char::len_utf8);
+                out.push_str(&line[i..next]);
+            }
+            '\'' if !in_double_quotes => {
+                next += line[next..]
+                    .find('\'')
+                    .map_or(line.len() - next, |len| len + 1);
+                out.push_str(&line[i..next]);
+            }
+            '"' => {
+                in_double_quotes = !in_double_quotes;
+                out.push(ch);
+            }
+            '$' if !in_double_quotes && line[next..].starts_with('\'') => {
+                match ansi_c_string(&line[next + 1..]) {
+                    Some((text, len)) => {
+                        out.push_str(&single_quote(&text, backslash));
+                        next += len + 2;
+                    }
+                    None => out.push(ch),
+                }
+            }
+            _ => out.push(ch),
+        }
+        i = next;
+    }
+
+    out
+}
+
+/// Interpret the escapes in the contents of a `$'...'` string starting
+/// `text`, returning the text and the length of the contents before the
+/// closing `'`, or `None` if there isn't one.
+fn ansi_c_string(text: &str) -> Option<(String, usize)> {
+    let mut out = String::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, ch)) = chars.next() {
+        if ch == '\'' {
+            return Some((out, i));
+        }
+        if ch != '\\' {
+            out.push(ch);
+            continue;
+        }
+
+        let (_, escape) = chars.next()?;
+        // Up to `max` digits in `radix` following `value`, as in `\x41`
+        let mut number = |radix, max, mut value: u32| {
+            let mut digits = 0;
+            while let Some(digit) = chars.peek().and_then(|(_, c)| c.to_digit(radix)) {
+                if digits == max {
+                    break;
+                }
+                value = value * radix + digit;
+                digits += 1;
+                chars.next();
+            }
+            (digits > 0).then_some(value)
+        };
+        let code = match escape {
+            'n' => Some(0x0a),
+            't' => Some(0x09),
+            'r' => Some(0x0d),
+            'a' => Some(0x07),
+            'b' => Some(0x08),
+            'e' | 'E' => Some(0x1b),
+            'f' => Some(0x0c),
+            'v' => Some(0x0b),
+            '0'..='7' => {
+                let first = escape.to_digit(8).unwrap_or_default();
+                Some(number(8, 2, first).unwrap_or(first))
+            }
+            'x' => number(16, 2, 0),
+            'u' => number(16, 4, 0),
+            'U' => number(16, 8, 0),
+            '\\' | '\'' | '"' | '?' => Some(escape.into()),
+            // Unknown escapes are kept as they are
+            _ => None,
+        };
+        let escaped = match code {
+            Some(code) => char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER),
+            None => {
+                out.push('\\');
+                escape
+            }
+        };
+        out.push(escaped);
+    }
+
+    None
+}
+
+/// Whether `text` contains any of the glob metacharacters `*`, `?` or a
+/// bracketed `[...]` character class.
+pub(crate) fn has_glob_chars(text: &str) -> bool {
+    text.contains(['*', '?']) || text.find('[').is_some_and(|i| text[i..].contains(']'))
+}
+
+/// Expand the glob `pattern` into the sorted list of paths matching it. Each
+/// `/`-separated component may use `*`, `?` and `[...]`. As in a shell, a
+/// wildcard doesn't match a leading `.` in a file name unless the pattern
+/// component itself starts with `.`. Relative patterns are matched in
+/// `current_dir`, and their matches stay relative.
+pub(crate) fn glob(pattern: &str, current_dir: &Path) -> Vec<String> {
+    let (root, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("", pattern),
+    };
+
+    let components: Vec<&str> = rest.split('/').collect();
+    let mut candidates = vec![root.to_string()];
+    for (i, component) in components.iter().enumerate() {
+        let separator = if i + 1 < components.len() { "/" } else { "" };
+
+        if !has_glob_chars(component) {
+            for candidate in &mut candidates {
+                candidate.push_str(component);
+                candidate.push_str(separator);
+            }
+            continue;
+        }
+
+        let component: Vec<char> = component.chars().collect();
+        let mut matches = vec![];
+        for candidate in &candidates {
+            let Ok(entries) = std::fs::read_dir(current_dir.join(candidate)) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if name.starts_with('.') && component.first() != Some(&'.') {
+                    continue;
+                }
+
+                let name_chars: Vec<char> = name.chars().collect();
+                if wildcard_match(&component, &name_chars) {
+                    matches.push(format!("{candidate}{name}{separator}"));
+                }
+            }
+        }
+        candidates = matches;
+    }
+
+    // Literal components after the last wildcard haven't been checked yet
+    candidates.retain(|c| current_dir.join(c).symlink_metadata().is_ok());
+    candidates.sort();
+    candidates
+}
+
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|i| wildcard_match(&pattern[1..], &name[i..])),
+        Some('?') => !name.is_empty() && wildcard_match(&pattern[1..], &name[1..]),
+        Some('[') => match match_class(&pattern[1..], name.first()) {
+            Some((matched, len)) => {
+                matched && !name.is_empty() && wildcard_match(&pattern[1 + len..], &name[1..])
+            }
+            // No closing `]`, so the `[` is literal
+            None => name.first() == Some(&'[') && wildcard_match(&pattern[1..], &name[1..]),
+        },
+        Some(c) => name.first() == Some(c) && wildcard_match(&pattern[1..], &name[1..]),
+    }
+}
+
+/// Match `ch` against the character class at the start of `class`, just after
+/// its opening `[`. Returns whether it matched and the length of the class
+/// including its closing `]`, or `None` if the class is never closed.
+fn match_class(class: &[char], ch: Option<&char>) -> Option<(bool, usize)> {
+    let (negated, mut i) = match class.first() {
+        Some('!' | '^') => (true, 1),
+        _ => (false, 0),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = *class.get(i)?;
+        if start == ']' && !first {
+            break;
+        }
+        first = false;
+
+        let end = match (class.get(i + 1), class.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                i += 2;
+                end
+            }
+            _ => start,
+        };
+        matched |= ch.is_some_and(|&c| start <= c && c <= end);
+        i += 1;
+    }
+
+    Some((matched != negated, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        wildcard_match(&pattern, &name)
+    }
+
+    #[test]
+    fn command_substitution() {
+        let mut run = |command: &str| match command {
+            "echo hi" => Ok("hi there\n".to_string()),
+            "echo $(echo hi)" => Ok("nested\n".to_string()),
+            "quote" => Ok(r#"it's "$x""#.to_string()),
+            _ => Err(ConsoleError::UnrecognizedCommand(command.to_string())),
+        };
+        let mut sub = |line: &str| substitute_commands(line, Backslash::Escape, &mut run).ok();
+
+        assert_eq!(sub("echo $(echo hi)").unwrap(), "echo 'hi' 'there'");
+        assert_eq!(sub("echo `echo hi`!").unwrap(), "echo 'hi' 'there'!");
+        assert_eq!(
+            sub(r#"echo "<$(echo hi)>""#).unwrap(),
+            r#"echo "<hi there>""#
+        );
+        assert_eq!(sub("echo $(echo $(echo hi))").unwrap(), "echo 'nested'");
+        assert_eq!(sub("echo '$(echo hi)'").unwrap(), "echo '$(echo hi)'");
+        assert_eq!(sub(r"echo \$(echo hi)").unwrap(), r"echo \$(echo hi)");
+        assert_eq!(sub("echo # $(echo hi)").unwrap(), "echo # $(echo hi)");
+        assert_eq!(sub("echo $(quote)").unwrap(), r#"echo 'it'\''s' '"$x"'"#);
+        assert_eq!(sub(r#"echo "$(quote)""#).unwrap(), r#"echo "it's \"\$x\"""#);
+        assert_eq!(sub("echo $(echo hi"), None);
+        assert_eq!(sub("echo $(nope)"), None);
+    }
+
+    #[test]
+    fn ansi_c_quoting() {
+        let quotes = |line| ansi_c_quotes(line, Backslash::Escape);
+        assert_eq!(quotes(r"echo $'a\tb'"), "echo 'a\tb'");
+        assert_eq!(quotes(r"echo $'it\'s' x"), r"echo 'it'\''s' x");
+        assert_eq!(quotes(r"echo $'\x41\101\u00e9\q'"), r"echo 'AAé\q'");
+        assert_eq!(quotes(r#"echo "$'a'" '$'\$'b'"#), r#"echo "$'a'" '$'\$'b'"#);
+        assert_eq!(quotes("echo $'open"), "echo $'open");
+    }
+
+    #[test]
+    fn ansi_c_quoting_with_literal_backslashes() {
+        let quotes = |line| ansi_c_quotes(line, Backslash::Literal);
+        assert_eq!(quotes(r"dir C:\a\'x' $'a\tb'"), "dir C:\\a\\'x' 'a\tb'");
+        assert_eq!(quotes(r"dir C:\a\$'b'"), r"dir C:\a\'b'");
+        assert_eq!(quotes(r"echo $'it\'s'"), r#"echo 'it'"'"'s'"#);
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(!matches("*.txt", "notes.md"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("[abc]x", "bx"));
+        assert!(matches("[a-c]x", "cx"));
+        assert!(!matches("[!a-c]x", "cx"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[x", "[x"));
+        assert!(matches("*", ""));
+    }
+}
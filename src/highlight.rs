@@ -0,0 +1,196 @@
+use std::borrow::Cow;
+
+use colored::Colorize;
+use rustyline::highlight::Highlighter;
+
+use crate::{
+    console::{find_command, AliasSet, CommandSet},
+    intrinsic::Intrinsic,
+    lexer,
+};
+
+/// Colors the line being typed: the name of each command of a pipeline green
+/// if it's recognized and red if not, and flags cyan. Hints are dimmed.
+pub(crate) struct CommandHighlighter {
+    commands: CommandSet,
+    aliases: AliasSet,
+    case_insensitive: bool,
+    enabled: bool,
+    dim_hints: bool,
+}
+
+impl CommandHighlighter {
+    pub fn new(commands: CommandSet, aliases: AliasSet) -> Self {
+        Self {
+            commands,
+            aliases,
+            case_insensitive: false,
+            enabled: false,
+            dim_hints: false,
+        }
+    }
+
+    /// Match command names regardless of case.
+    pub fn with_case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_dim_hints(mut self, dim_hints: bool) -> Self {
+        self.dim_hints = dim_hints;
+        self
+    }
+
+    /// Whether `name` is a command, intrinsic or alias.
+    fn is_known(&self, name: &str) -> bool {
+        find_command(&self.commands.borrow(), name, self.case_insensitive).is_some()
+            || Intrinsic::find(name, self.case_insensitive).is_some()
+            || self.aliases.borrow().contains_key(name)
+    }
+
+    fn highlight_word(&self, word: &str, is_first: bool) -> String {
+        if is_first {
+            // External commands can't be checked without searching `PATH`
+            if word.starts_with('!') {
+                word.to_string()
+            } else if self.is_known(word) {
+                word.green().to_string()
+            } else {
+                word.red().to_string()
+            }
+        } else if word.len() > 1 && word.starts_with('-') && word.parse::<f64>().is_err() {
+            word.cyan().to_string()
+        } else {
+            word.to_string()
+        }
+    }
+}
+
+impl Highlighter for CommandHighlighter {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if !self.enabled {
+            return Cow::Borrowed(line);
+        }
+
+        let mut highlighted = String::new();
+        let mut is_first = true;
+        for piece in pieces(line) {
+            match piece {
+                Piece::Word(word) => {
+                    highlighted.push_str(&self.highlight_word(word, is_first));
+                    is_first = false;
+                }
+                Piece::Pipe => {
+                    highlighted.push('|');
+                    is_first = true;
+                }
+                Piece::Other(text) => highlighted.push_str(text),
+            }
+        }
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        if self.dim_hints {
+            Cow::Owned(hint.dimmed().to_string())
+        } else {
+            Cow::Borrowed(hint)
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, _forced: bool) -> bool {
+        // Any edit can change whether a command is recognized
+        self.enabled
+    }
+}
+
+/// A part of a command line. Joined back together, the pieces of a line are
+/// the line itself.
+#[derive(Debug, PartialEq, Eq)]
+enum Piece<'a> {
+    /// A word, as typed, with any quotes or escapes in it.
+    Word(&'a str),
+    /// A `|` separating the stages of a pipeline.
+    Pipe,
+    /// Whitespace or a comment between words.
+    Other(&'a str),
+}
+
+/// Split `line` into pieces. Unlike [`lexer::split`], this can't fail, since
+/// the line may be only partly typed.
+fn pieces(line: &str) -> Vec<Piece<'_>> {
+    let mut pieces = vec![];
+    let mut word_start = None;
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, ch) in line.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        match (quote, ch) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"') | None, '\\') => escaped = true,
+            (Some(_), _) => (),
+            (None, '#') if word_start.is_none() => {
+                pieces.push(Piece::Other(&line[i..]));
+                return pieces;
+            }
+            (None, _) if ch == '|' || lexer::is_blank(ch) => {
+                if let Some(start) = word_start.take() {
+                    pieces.push(Piece::Word(&line[start..i]));
+                }
+                if ch == '|' {
+                    pieces.push(Piece::Pipe);
+                } else {
+                    pieces.push(Piece::Other(&line[i..i + ch.len_utf8()]));
+                }
+                continue;
+            }
+            (None, '\'' | '"') => quote = Some(ch),
+            (None, _) => (),
+        }
+        word_start.get_or_insert(i);
+    }
+
+    if let Some(start) = word_start {
+        pieces.push(Piece::Word(&line[start..]));
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lines_into_pieces() {
+        assert_eq!(
+            pieces("ls -l 'a|b'|  !wc # x"),
+            [
+                Piece::Word("ls"),
+                Piece::Other(" "),
+                Piece::Word("-l"),
+                Piece::Other(" "),
+                Piece::Word("'a|b'"),
+                Piece::Pipe,
+                Piece::Other(" "),
+                Piece::Other(" "),
+                Piece::Word("!wc"),
+                Piece::Other(" "),
+                Piece::Other("# x"),
+            ]
+        );
+        assert_eq!(
+            pieces(r#"a\ b "c"#),
+            [Piece::Word(r"a\ b"), Piece::Other(" "), Piece::Word("\"c")]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Ending the prompt from another thread once it's been left idle for a while.
+//!
+//! Reading a line blocks the console's thread, so an [`IdleTimeout`] is
+//! watched from a thread of its own. Key presses count as activity, so the
+//! timeout only passes while nobody types. rustyline can't be told to stop
+//! reading, so on Unix the read is ended by pointing stdin at `/dev/null` and
+//! waking the editor with an external print: it then sees the end of input.
+//! [`Stdin`] puts the terminal back afterwards. On other platforms, or when
+//! stdin isn't a terminal, a read can't be ended early.
+
+use std::{
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rustyline::{
+    Cmd, ConditionalEventHandler, Event, EventContext, EventHandler, ExternalPrinter, RepeatCount,
+};
+
+use crate::console::ConsoleEditor;
+
+pub(crate) type IdleCallback = dyn FnMut() -> bool + Send;
+
+pub(crate) struct IdleTimeout {
+    timeout: Duration,
+    callback: Box<IdleCallback>,
+    /// When a key was last pressed at the prompt.
+    activity: Arc<Mutex<Instant>>,
+}
+
+impl IdleTimeout {
+    pub fn new(timeout: Duration, callback: Box<IdleCallback>) -> Self {
+        Self {
+            timeout,
+            callback,
+            activity: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Have `rl` record key presses as activity. Keys with a binding of their
+    /// own aren't seen, since rustyline only falls back to `Event::Any` for
+    /// the others.
+    pub fn track_activity(&self, rl: &mut ConsoleEditor) {
+        let inner = rl.unbind_sequence(Event::Any);
+        let activity = Activity {
+            last: Arc::clone(&self.activity),
+            inner,
+        };
+        rl.bind_sequence(Event::Any, EventHandler::Conditional(Box::new(activity)));
+    }
+
+    /// Call `read` on the current thread while another calls the callback
+    /// each time the timeout passes without activity, until it returns `true`
+    /// and `end_read` is called. Returns what `read` did and whether the
+    /// callback returned `true`.
+    pub fn watch<T>(
+        &mut self,
+        read: impl FnOnce() -> T,
+        end_read: impl FnOnce() + Send,
+    ) -> (T, bool) {
+        let timeout = self.timeout;
+        let callback = &mut self.callback;
+        let activity = &*self.activity;
+        touch(activity);
+
+        let (done, wait) = mpsc::channel::<()>();
+        std::thread::scope(|s| {
+            let watcher = s.spawn(move || loop {
+                let idle = lock(activity).elapsed();
+                if idle < timeout {
+                    match wait.recv_timeout(timeout - idle) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                        // `read` returned
+                        _ => return false,
+                    }
+                }
+
+                if callback() {
+                    end_read();
+                    return true;
+                }
+                touch(activity);
+            });
+
+            let result = read();
+            drop(done);
+            let timed_out = watcher
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            (result, timed_out)
+        })
+    }
+}
+
+fn lock(activity: &Mutex<Instant>) -> std::sync::MutexGuard<'_, Instant> {
+    activity.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn touch(activity: &Mutex<Instant>) {
+    *lock(activity) = Instant::now();
+}
+
+/// Records each key press as activity, then does what the binding it
+/// replaced did, or what the key does by default.
+struct Activity {
+    last: Arc<Mutex<Instant>>,
+    inner: Option<EventHandler>,
+}
+
+impl ConditionalEventHandler for Activity {
+    fn handle(
+        &self,
+        evt: &Event,
+        n: RepeatCount,
+        positive: bool,
+        ctx: &EventContext,
+    ) -> Option<Cmd> {
+        touch(&self.last);
+        match &self.inner {
+            Some(EventHandler::Simple(cmd)) => Some(cmd.clone()),
+            Some(EventHandler::Conditional(handler)) => handler.handle(evt, n, positive, ctx),
+            None => None,
+        }
+    }
+}
+
+/// The terminal that a line is read from, saved so that the read can be
+/// ended and the terminal then restored.
+pub(crate) struct Stdin(sys::Saved);
+
+impl Stdin {
+    /// Save stdin if it's a terminal whose reads can be ended.
+    pub fn save() -> Option<Self> {
+        sys::save().map(Self)
+    }
+
+    /// End the read that `printer`'s editor is blocked in.
+    pub fn end_read(&self, printer: &mut impl ExternalPrinter) {
+        sys::replace_with_null();
+        // Wakes the editor, which then reads from the replaced stdin
+        let _ = printer.print(String::new());
+    }
+
+    /// Put stdin back once the read returned. After it was `ended`, the
+    /// editor couldn't restore the terminal's settings itself.
+    pub fn restore(self, ended: bool) {
+        sys::restore(self.0, ended);
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{
+        ffi::{c_char, c_int},
+        io::Write as _,
+    };
+
+    // The same on every Unix
+    const STDIN_FILENO: c_int = 0;
+    const O_RDONLY: c_int = 0;
+    // Linux and the BSDs, including macOS
+    const TCSANOW: c_int = 0;
+
+    /// What rustyline turns bracketed paste on with, and off when it's done.
+    const BRACKETED_PASTE_OFF: &str = "\x1b[?2004l";
+
+    /// A `termios`, whose layout differs between platforms, as bytes
+    /// enough for any of them.
+    #[repr(C, align(8))]
+    pub struct Termios([u8; 256]);
+
+    extern "C" {
+        fn isatty(fd: c_int) -> c_int;
+        fn dup(fd: c_int) -> c_int;
+        fn dup2(fd: c_int, new_fd: c_int) -> c_int;
+        fn open(path: *const c_char, flags: c_int, ...) -> c_int;
+        fn close(fd: c_int) -> c_int;
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, action: c_int, termios: *const Termios) -> c_int;
+    }
+
+    /// A copy of stdin and the terminal's settings before the read.
+    pub struct Saved {
+        fd: c_int,
+        termios: Box<Termios>,
+    }
+
+    pub fn save() -> Option<Saved> {
+        let mut termios = Box::new(Termios([0; 256]));
+        // SAFETY: `termios` is larger than the struct it's written as
+        unsafe {
+            if isatty(STDIN_FILENO) == 0 || tcgetattr(STDIN_FILENO, &mut *termios) != 0 {
+                return None;
+            }
+            let fd = dup(STDIN_FILENO);
+            (fd >= 0).then_some(Saved { fd, termios })
+        }
+    }
+
+    pub fn replace_with_null() {
+        // SAFETY: only file descriptors are touched, and stdin stays open
+        unsafe {
+            let null = open(c"/dev/null".as_ptr(), O_RDONLY);
+            if null >= 0 {
+                dup2(null, STDIN_FILENO);
+                close(null);
+            }
+        }
+    }
+
+    pub fn restore(saved: Saved, ended: bool) {
+        // SAFETY: `saved.fd` is the copy of stdin made by `save`, and
+        // `saved.termios` was filled in by `tcgetattr`
+        unsafe {
+            dup2(saved.fd, STDIN_FILENO);
+            close(saved.fd);
+            if ended {
+                tcsetattr(STDIN_FILENO, TCSANOW, &*saved.termios);
+            }
+        }
+
+        if ended {
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(BRACKETED_PASTE_OFF.as_bytes());
+            let _ = stdout.flush();
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    pub enum Saved {}
+
+    pub fn save() -> Option<Saved> {
+        None
+    }
+
+    pub fn replace_with_null() {}
+
+    pub fn restore(saved: Saved, _ended: bool) {
+        match saved {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn callbacks_run_until_they_end_the_read() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let mut idle = IdleTimeout::new(
+            Duration::from_millis(10),
+            Box::new(move || counted.fetch_add(1, Ordering::SeqCst) == 1),
+        );
+        let (end, ended) = mpsc::channel();
+        let (line, timed_out) = idle.watch(
+            || {
+                // Stands in for a read that ends when stdin is replaced
+                ended.recv().unwrap();
+                "line"
+            },
+            move || end.send(()).unwrap(),
+        );
+        assert_eq!((line, timed_out), ("line", true));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Reading before the timeout doesn't call it
+        idle.timeout = Duration::from_secs(10);
+        let (_, timed_out) = idle.watch(|| (), || ());
+        assert!(!timed_out);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn activity_resets_the_timeout() {
+        let mut idle = IdleTimeout::new(Duration::from_millis(50), Box::new(|| true));
+        let activity = Arc::clone(&idle.activity);
+        let (_, timed_out) = idle.watch(
+            || {
+                for _ in 0..20 {
+                    std::thread::sleep(Duration::from_millis(10));
+                    touch(&activity);
+                }
+            },
+            || (),
+        );
+        assert!(!timed_out);
+    }
+}
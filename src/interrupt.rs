@@ -0,0 +1,100 @@
+//! Catching Ctrl-C while an external command runs, so that it interrupts the
+//! command rather than the console.
+//!
+//! The terminal sends `SIGINT` to the console and the command alike, and by
+//! default it ends both. While an [`InterruptGuard`] is held, the console
+//! instead records that it was interrupted, and kills the command if it
+//! didn't end by itself. Signals are only caught on Unix; on other platforms
+//! Ctrl-C still ends the console, as it does while a command that isn't
+//! external runs.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+/// How many times `SIGINT` has been caught.
+static INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+/// How many guards are held, and the handler that was installed before the
+/// first of them.
+static GUARDS: Mutex<(usize, usize)> = Mutex::new((0, 0));
+
+/// Catches `SIGINT` for as long as it's held. Guards may be held by several
+/// threads at once, e.g. for the stages of a streaming pipeline.
+pub(crate) struct InterruptGuard {
+    interrupts: u64,
+}
+
+impl InterruptGuard {
+    pub fn new() -> Self {
+        let mut guards = GUARDS.lock().unwrap_or_else(|e| e.into_inner());
+        if guards.0 == 0 {
+            guards.1 = sys::catch_interrupts();
+        }
+        guards.0 += 1;
+
+        Self {
+            interrupts: INTERRUPTS.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Whether `SIGINT` was caught since the guard was created.
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTS.load(Ordering::SeqCst) != self.interrupts
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        let mut guards = GUARDS.lock().unwrap_or_else(|e| e.into_inner());
+        guards.0 -= 1;
+        if guards.0 == 0 {
+            sys::restore(guards.1);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::{ffi::c_int, sync::atomic::Ordering};
+
+    use super::INTERRUPTS;
+
+    // The same on every Unix
+    const SIGINT: c_int = 2;
+
+    extern "C" {
+        // `sighandler_t` is a function pointer, or a constant like `SIG_DFL`
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_interrupt(_signum: c_int) {
+        INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Catch `SIGINT`, returning the handler it replaced. Unlike an ignored
+    /// signal, a caught one is reset to its default in the commands spawned
+    /// meanwhile, so they can still be interrupted.
+    pub fn catch_interrupts() -> usize {
+        // SAFETY: the handler only touches an atomic, which is async-signal-safe
+        unsafe { signal(SIGINT, on_interrupt as extern "C" fn(c_int) as usize) }
+    }
+
+    /// Reinstall the `previous` handler of `SIGINT`.
+    pub fn restore(previous: usize) {
+        // SAFETY: `previous` was returned by `signal`
+        unsafe {
+            signal(SIGINT, previous);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    pub fn catch_interrupts() -> usize {
+        0
+    }
+
+    pub fn restore(_previous: usize) {}
+}
@@ -0,0 +1,295 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    console::{AliasMap, CommandSet},
+    expand,
+    lexer::Backslash,
+};
+
+/// Commands built into the console itself. Unlike a user-defined
+/// [`Command`](crate::console::Command), these need access to the console's
+/// internal state, so they are dispatched by the console directly.
+///
+/// A registered command with the same name always takes precedence over an
+/// intrinsic.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Intrinsic {
+    Alias,
+    Cd,
+    Config,
+    Exit,
+    Help,
+    Pwd,
+    Repeat,
+    Source,
+    Stats,
+    Unalias,
+    Which,
+}
+
+impl Intrinsic {
+    pub(crate) const ALL: &'static [Intrinsic] = &[
+        Intrinsic::Alias,
+        Intrinsic::Cd,
+        Intrinsic::Config,
+        Intrinsic::Exit,
+        Intrinsic::Help,
+        Intrinsic::Pwd,
+        Intrinsic::Repeat,
+        Intrinsic::Source,
+        Intrinsic::Stats,
+        Intrinsic::Unalias,
+        Intrinsic::Which,
+    ];
+
+    pub(crate) fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|i| i.name() == name)
+    }
+
+    /// Like [`Intrinsic::from_name`], optionally ignoring case.
+    pub(crate) fn find(name: &str, case_insensitive: bool) -> Option<Self> {
+        if case_insensitive {
+            let name = name.to_lowercase();
+            Self::ALL.iter().copied().find(|i| i.name() == name)
+        } else {
+            Self::from_name(name)
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Intrinsic::Alias => "alias",
+            Intrinsic::Cd => "cd",
+            Intrinsic::Config => "config",
+            Intrinsic::Exit => "exit",
+            Intrinsic::Help => "help",
+            Intrinsic::Pwd => "pwd",
+            Intrinsic::Repeat => "repeat",
+            Intrinsic::Source => "source",
+            Intrinsic::Stats => "stats",
+            Intrinsic::Unalias => "unalias",
+            Intrinsic::Which => "which",
+        }
+    }
+
+    pub(crate) fn parser(self) -> clap::Command {
+        match self {
+            Intrinsic::Alias => clap::Command::new(self.name())
+                .about("Define aliases, or list them")
+                .arg(
+                    clap::Arg::new("definition")
+                        .help("`name=value` to define an alias, or `name` to show one")
+                        .num_args(1..),
+                ),
+            Intrinsic::Cd => {
+                clap::Command::new(self.name())
+                    .about("Change the directory external commands run in")
+                    .arg(clap::Arg::new("dir").help(
+                        "Directory to change to, `-` for the previous one, or home if omitted",
+                    ))
+            }
+            Intrinsic::Config => {
+                clap::Command::new(self.name()).about("Show the console's configuration")
+            }
+            Intrinsic::Exit => clap::Command::new(self.name())
+                .about("Leave the current sub-console, or end the session"),
+            Intrinsic::Help => clap::Command::new(self.name())
+                .about("List the available commands, or show help for one")
+                .arg(clap::Arg::new("command").help("Command to show help for")),
+            Intrinsic::Pwd => clap::Command::new(self.name())
+                .about("Print the directory external commands run in"),
+            Intrinsic::Repeat => clap::Command::new(self.name())
+                .about("Run a command several times, concatenating its output")
+                .arg(
+                    clap::Arg::new("count")
+                        .help("How many times to run the command")
+                        .required(true)
+                        .value_parser(clap::value_parser!(u32).range(..=MAX_REPEAT_COUNT)),
+                )
+                .arg(
+                    clap::Arg::new("command")
+                        .help("Command to run, with its arguments")
+                        .required(true)
+                        .num_args(1..)
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true),
+                ),
+            Intrinsic::Source => clap::Command::new(self.name())
+                .about("Run the commands in a script file in this session")
+                .arg(
+                    clap::Arg::new("file")
+                        .help("Script to run, relative to the current directory")
+                        .required(true),
+                ),
+            Intrinsic::Stats => {
+                clap::Command::new(self.name()).about("Show how many times each command has run")
+            }
+            Intrinsic::Unalias => clap::Command::new(self.name()).about("Remove aliases").arg(
+                clap::Arg::new("name")
+                    .help("Aliases to remove")
+                    .required(true)
+                    .num_args(1..),
+            ),
+            Intrinsic::Which => clap::Command::new(self.name())
+                .about("Report how each name resolves")
+                .arg(
+                    clap::Arg::new("name")
+                        .help("Names to look up")
+                        .required(true)
+                        .num_args(1..),
+                ),
+        }
+    }
+}
+
+/// The most times `repeat` can run a command, which stops a mistyped count from
+/// running one practically forever.
+const MAX_REPEAT_COUNT: i64 = 10_000;
+
+/// The heading under which commands without a category are listed by `help`.
+const DEFAULT_CATEGORY: &str = "General";
+
+/// The heading under which intrinsics are listed by `help`.
+const INTRINSIC_CATEGORY: &str = "Built-in";
+
+/// List every command with its description, grouped under category headings.
+/// Categories and the commands within them are sorted alphabetically.
+pub(crate) fn help_overview(commands: &CommandSet) -> String {
+    let command_set = commands.borrow();
+    let mut categories: BTreeMap<String, BTreeMap<&str, clap::Command>> = BTreeMap::new();
+
+    for (name, cmd) in command_set.iter() {
+        let category = cmd
+            .category()
+            .unwrap_or_else(|| DEFAULT_CATEGORY.to_string());
+        categories
+            .entry(category)
+            .or_default()
+            .insert(name, cmd.get_parser());
+    }
+    for intrinsic in Intrinsic::ALL {
+        if !command_set.contains_key(intrinsic.name()) {
+            categories
+                .entry(INTRINSIC_CATEGORY.to_string())
+                .or_default()
+                .insert(intrinsic.name(), intrinsic.parser());
+        }
+    }
+
+    let width = categories
+        .values()
+        .flat_map(|cmds| cmds.keys())
+        .map(|name| name.len())
+        .max()
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    for (i, (category, cmds)) in categories.iter().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let _ = writeln!(out, "{category}:");
+        for (name, parser) in cmds {
+            let about = parser
+                .get_about()
+                .map(|a| a.to_string())
+                .unwrap_or_default();
+            let _ = writeln!(out, "  {name:width$}  {about}");
+        }
+    }
+
+    out
+}
+
+/// The full help text of the command or intrinsic called `name`, followed by
+/// the command's extended documentation, if any.
+pub(crate) fn help_for(commands: &CommandSet, name: &str) -> Option<String> {
+    let (parser, long_help) = match commands.borrow().get(name) {
+        Some(cmd) => (cmd.get_parser(), cmd.long_help()),
+        None => (Intrinsic::from_name(name)?.parser(), None),
+    };
+
+    let mut help = parser.bin_name(name).render_long_help().to_string();
+    if let Some(long_help) = long_help {
+        help.push('\n');
+        help.push_str(long_help.trim_end());
+        help.push('\n');
+    }
+    Some(help)
+}
+
+/// Format an alias definition the way `alias` accepts it, quoting `value`.
+pub(crate) fn alias_definition(name: &str, value: &str, backslash: Backslash) -> String {
+    format!("alias {name}={}", expand::single_quote(value, backslash))
+}
+
+/// Describe how `name` would be run: as an alias, a registered command, an
+/// intrinsic, or an external program found on `PATH`. A leading `!` only
+/// searches `PATH`.
+pub(crate) fn which(commands: &CommandSet, aliases: &AliasMap, name: &str) -> String {
+    if let Some(program) = name.strip_prefix('!') {
+        return match find_in_path(program) {
+            Some(path) => format!("{program} is {}", path.display()),
+            None => format!("{program} not found"),
+        };
+    }
+
+    if let Some(value) = aliases.get(name) {
+        format!("{name} is aliased to `{value}`")
+    } else if commands.borrow().contains_key(name) {
+        format!("{name} is a registered command")
+    } else if Intrinsic::from_name(name).is_some() {
+        format!("{name} is a console built-in")
+    } else if let Some(path) = find_in_path(name) {
+        format!("{name} is {}", path.display())
+    } else {
+        format!("{name} not found")
+    }
+}
+
+/// Search the directories in `PATH` for an executable called `name`.
+pub(crate) fn find_in_path(name: &str) -> Option<PathBuf> {
+    if name.is_empty() {
+        return None;
+    }
+
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// The names of all the executables in the directories in `PATH`, sorted and
+/// without duplicates.
+pub(crate) fn executables_in_path() -> Vec<String> {
+    let Some(path) = std::env::var_os("PATH") else {
+        return vec![];
+    };
+
+    let mut names: Vec<String> = std::env::split_paths(&path)
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.flatten())
+        .filter(|entry| is_executable(&entry.path()))
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
@@ -0,0 +1,265 @@
+/// A word of a command line, with its quotes and escapes removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Word {
+    pub text: String,
+    /// Whether any part of the word was quoted or escaped. Quoted words are
+    /// taken literally and never expanded.
+    pub quoted: bool,
+}
+
+/// Why a line couldn't be split into words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Incomplete {
+    /// A quote was opened but never closed.
+    Quote,
+    /// The line ends in a backslash outside of quotes.
+    Escape,
+}
+
+/// How a backslash outside of single quotes is lexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backslash {
+    /// It escapes the next character, as in a POSIX shell.
+    Escape,
+    /// It's an ordinary character, as in Windows paths like `C:\Users`.
+    Literal,
+}
+
+/// Whether `ch` separates words outside of quotes. Other whitespace, such as
+/// `\r`, is part of a word.
+pub(crate) fn is_blank(ch: char) -> bool {
+    matches!(ch, ' ' | '\t' | '\n')
+}
+
+/// Split `line` into words following the same POSIX-like rules as
+/// [`shlex::split`], while also recording which words were quoted. Returns
+/// `None` on an unterminated quote or a trailing backslash.
+pub(crate) fn split(line: &str) -> Option<Vec<Word>> {
+    split_words(line, Backslash::Escape).ok()
+}
+
+/// Split `line` into words like [`split`], lexing backslashes as `backslash`
+/// says. With [`Backslash::Literal`], a quote can only be quoted with the
+/// other kind of quote.
+pub(crate) fn split_with(line: &str, backslash: Backslash) -> Option<Vec<Word>> {
+    split_words(line, backslash).ok()
+}
+
+/// Whether `line` ends inside a quote that hasn't been closed yet.
+pub(crate) fn ends_in_open_quote(line: &str, backslash: Backslash) -> bool {
+    split_words(line, backslash) == Err(Incomplete::Quote)
+}
+
+fn split_words(line: &str, backslash: Backslash) -> Result<Vec<Word>, Incomplete> {
+    let mut chars = line.chars();
+    let mut words = vec![];
+
+    'words: loop {
+        // Skip whitespace and comments between words
+        let mut ch = loop {
+            match chars.next() {
+                Some(' ' | '\t' | '\n') => (),
+                Some('#') => {
+                    if !chars.any(|c| c == '\n') {
+                        break 'words;
+                    }
+                }
+                Some(c) => break c,
+                None => break 'words,
+            }
+        };
+
+        let mut word = Word {
+            text: String::new(),
+            quoted: false,
+        };
+        loop {
+            match ch {
+                '\'' => {
+                    word.quoted = true;
+                    loop {
+                        match chars.next().ok_or(Incomplete::Quote)? {
+                            '\'' => break,
+                            c => word.text.push(c),
+                        }
+                    }
+                }
+                '"' => {
+                    word.quoted = true;
+                    loop {
+                        match chars.next().ok_or(Incomplete::Quote)? {
+                            '"' => break,
+                            '\\' if backslash == Backslash::Escape => {
+                                match chars.next().ok_or(Incomplete::Quote)? {
+                                    c @ ('$' | '`' | '"' | '\\') => word.text.push(c),
+                                    '\n' => (),
+                                    c => {
+                                        word.text.push('\\');
+                                        word.text.push(c);
+                                    }
+                                }
+                            }
+                            c => word.text.push(c),
+                        }
+                    }
+                }
+                '\\' if backslash == Backslash::Escape => {
+                    word.quoted = true;
+                    match chars.next().ok_or(Incomplete::Escape)? {
+                        '\n' => (),
+                        c => word.text.push(c),
+                    }
+                }
+                ' ' | '\t' | '\n' => break,
+                c => word.text.push(c),
+            }
+
+            match chars.next() {
+                Some(c) => ch = c,
+                None => break,
+            }
+        }
+
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+/// The word at the end of a line that is still being typed, e.g. for
+/// completion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PartialWord {
+    /// The byte index in the line at which the word starts.
+    pub start: usize,
+    /// The word so far, with its quotes and escapes removed.
+    pub text: String,
+    /// Whether any part of the word was quoted or escaped.
+    pub quoted: bool,
+    /// The quote the word is still inside of, if any.
+    pub open_quote: Option<char>,
+}
+
+/// Find the word at the end of `line`, which may be inside a quote that hasn't
+/// been closed yet. If `line` ends in whitespace, the word is empty and
+/// starts at the end of the line.
+pub(crate) fn partial_word(line: &str) -> PartialWord {
+    let mut word = PartialWord {
+        start: line.len(),
+        text: String::new(),
+        quoted: false,
+        open_quote: None,
+    };
+    let mut in_word = false;
+
+    let mut chars = line.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        if !in_word && !is_blank(ch) {
+            in_word = true;
+            word.start = i;
+            word.text.clear();
+            word.quoted = false;
+        }
+
+        match (word.open_quote, ch) {
+            (None, ' ' | '\t' | '\n') => in_word = false,
+            (None, '\'' | '"') => {
+                word.quoted = true;
+                word.open_quote = Some(ch);
+            }
+            (Some(quote), _) if ch == quote => word.open_quote = None,
+            (Some('"'), '\\') => match chars.next() {
+                Some((_, c @ ('$' | '`' | '"' | '\\'))) => word.text.push(c),
+                Some((_, '\n')) => (),
+                Some((_, c)) => {
+                    word.text.push('\\');
+                    word.text.push(c);
+                }
+                None => (),
+            },
+            (None, '\\') => {
+                word.quoted = true;
+                if let Some((_, c)) = chars.next().filter(|&(_, c)| c != '\n') {
+                    word.text.push(c);
+                }
+            }
+            (_, c) => word.text.push(c),
+        }
+    }
+
+    if !in_word {
+        word.start = line.len();
+        word.text.clear();
+        word.quoted = false;
+    }
+
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(line: &str) -> Option<Vec<String>> {
+        split(line).map(|words| words.into_iter().map(|w| w.text).collect())
+    }
+
+    #[test]
+    fn matches_shlex() {
+        for line in [
+            "",
+            "   ",
+            "echo hi",
+            "  echo   'a b'  \"c d\" ",
+            r#"echo "a \"b\" \$c \d" e\ f"#,
+            "echo a#b # comment",
+            "echo 'café' ünï",
+            "echo \"unterminated",
+            "echo 'unterminated",
+            "echo trailing\\",
+        ] {
+            assert_eq!(texts(line), shlex::split(line), "{line:?}");
+        }
+    }
+
+    #[test]
+    fn detects_open_quotes() {
+        assert!(ends_in_open_quote("echo 'a", Backslash::Escape));
+        assert!(ends_in_open_quote("echo \"a 'b' \\\"", Backslash::Escape));
+        assert!(ends_in_open_quote("echo 'a\\", Backslash::Escape));
+        assert!(!ends_in_open_quote("echo 'a'", Backslash::Escape));
+        assert!(!ends_in_open_quote("echo a\\", Backslash::Escape));
+        assert!(!ends_in_open_quote("echo # don't", Backslash::Escape));
+        assert!(!ends_in_open_quote(r#"echo "a\""#, Backslash::Literal));
+    }
+
+    #[test]
+    fn splits_with_literal_backslashes() {
+        let words = split_with(
+            r#"!dir C:\Users\me "C:\Program Files\" '\'"#,
+            Backslash::Literal,
+        );
+        let texts: Vec<String> = words.unwrap().into_iter().map(|w| w.text).collect();
+        assert_eq!(texts, ["!dir", r"C:\Users\me", r"C:\Program Files\", r"\"]);
+    }
+
+    #[test]
+    fn finds_partial_words() {
+        let word = |line| {
+            let word = partial_word(line);
+            (word.start, word.text, word.open_quote)
+        };
+
+        assert_eq!(word("cat my\\ fi"), (4, "my fi".to_string(), None));
+        assert_eq!(word("cat \"my fi"), (4, "my fi".to_string(), Some('"')));
+        assert_eq!(word("cat a'b c"), (4, "ab c".to_string(), Some('\'')));
+        assert_eq!(word("cat 'a b' "), (10, String::new(), None));
+    }
+
+    #[test]
+    fn records_quoting() {
+        let words = split(r#"~ '~' "~" \~ a"b""#).unwrap();
+        let quoted: Vec<bool> = words.iter().map(|w| w.quoted).collect();
+        assert_eq!(quoted, [false, true, true, true, true]);
+    }
+}
@@ -1,10 +1,53 @@
+#[cfg(feature = "async")]
+pub mod async_console;
+#[cfg(feature = "builtins")]
+pub mod builtins;
 mod completion;
 pub mod console;
+mod expand;
+mod highlight;
+mod idle;
+mod interrupt;
+mod intrinsic;
+mod lexer;
+mod macros;
+pub mod streaming;
 
 pub extern crate clap;
+pub extern crate rustyline;
 
 #[cfg(test)]
 mod tests {
+    use std::path::{Path, PathBuf};
+
+    /// A directory for a test's files, removed when it's dropped, even if the
+    /// test fails.
+    pub(crate) struct TempDir(PathBuf);
+
+    impl TempDir {
+        /// Create an empty directory named after `name` and the process, so
+        /// that runs at the same time don't share it.
+        pub fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("cmd3-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
 
     #[test]
     fn it_works() {}
@@ -0,0 +1,76 @@
+/// Define a simple [`Command`] from its name, a type deriving
+/// [`clap::Parser`] for its arguments and a closure that runs it, without
+/// implementing the trait by hand. The closure is called with the parsed
+/// arguments and the command's [`ExecContext`].
+///
+/// ```
+/// use cmd3::console::Console;
+///
+/// /// Write the arguments, separated by spaces.
+/// #[derive(clap::Parser)]
+/// struct EchoArgs {
+///     arg: Vec<String>,
+/// }
+///
+/// let console = Console::default().add_command(Box::new(cmd3::command!(
+///     "echo",
+///     EchoArgs,
+///     |args, ctx| {
+///         writeln!(ctx.stdout, "{}", args.arg.join(" "))?;
+///         Ok(())
+///     }
+/// )));
+/// ```
+///
+/// Commands that need any of the trait's other methods, such as
+/// [`Command::long_help`], still implement it themselves.
+///
+/// [`Command`]: crate::console::Command
+/// [`Command::long_help`]: crate::console::Command::long_help
+/// [`ExecContext`]: crate::console::ExecContext
+#[macro_export]
+macro_rules! command {
+    ($name:expr, $args:ty, $execute:expr $(,)?) => {{
+        struct MacroCommand<F>(F);
+
+        impl<F> $crate::console::Command for MacroCommand<F>
+        where
+            F: Fn(
+                $args,
+                &mut $crate::console::ExecContext<'_>,
+            )
+                -> ::std::result::Result<(), ::std::boxed::Box<dyn ::std::error::Error>>,
+        {
+            fn get_name(&self) -> ::std::string::String {
+                ::std::string::ToString::to_string($name)
+            }
+
+            fn get_parser(&self) -> $crate::clap::Command {
+                <$args as $crate::clap::CommandFactory>::command().name($name)
+            }
+
+            fn execute(
+                &self,
+                args: $crate::clap::ArgMatches,
+                ctx: &mut $crate::console::ExecContext<'_>,
+            ) -> ::std::result::Result<(), ::std::boxed::Box<dyn ::std::error::Error>> {
+                let args = <$args as $crate::clap::FromArgMatches>::from_arg_matches(&args)?;
+                (self.0)(args, ctx)
+            }
+        }
+
+        // Gives the closure's parameters their types
+        fn new<F>(execute: F) -> MacroCommand<F>
+        where
+            F: Fn(
+                $args,
+                &mut $crate::console::ExecContext<'_>,
+            )
+                -> ::std::result::Result<(), ::std::boxed::Box<dyn ::std::error::Error>>,
+        {
+            MacroCommand(execute)
+        }
+
+        new($execute)
+    }};
+}
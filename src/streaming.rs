@@ -0,0 +1,318 @@
+//! Pipelines whose stages run at the same time, passing lines on as soon as
+//! they're produced.
+//!
+//! By default, each stage of a pipeline runs to completion before the next
+//! starts, and its whole output is passed on at once. With
+//! [`Console::with_streaming`], commands that implement [`StreamingCommand`]
+//! instead each run on their own thread, connected by channels, so that e.g.
+//! `producer | filter | consumer` prints lines as they're produced. Any other
+//! stages, such as external commands, still run buffered on the console's
+//! thread.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::mpsc::{self, Receiver, SendError, Sender},
+    thread::{self, ScopedJoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::console::{report_timings, Console, ConsoleError, Runnable, StderrWriter};
+
+/// The error returned by [`StreamingCommand::execute_streaming`], which is
+/// sent back to the console's thread.
+pub type StreamingError = Box<dyn Error + Send + Sync>;
+
+/// A command that can run at the same time as the other stages of its
+/// pipeline. It's registered like any other [`Command`], which returns
+/// itself from [`Command::as_streaming`]. Its [`Command::execute`] is still
+/// used when streaming is disabled.
+///
+/// [`Command`]: crate::console::Command
+/// [`Command::as_streaming`]: crate::console::Command::as_streaming
+/// [`Command::execute`]: crate::console::Command::execute
+pub trait StreamingCommand: Send + Sync {
+    /// Run the command on its own thread. `stdin` receives the lines of the
+    /// previous stage's output, without their newlines, and each line sent to
+    /// `stdout` is passed on to the next stage straight away.
+    ///
+    /// Sending fails once the next stage has stopped reading, e.g. after
+    /// taking the lines it needs. Returning that error, as `?` does, ends the
+    /// command without it being reported. Signals like
+    /// [`Control::StopPipeline`](crate::console::Control::StopPipeline) can't
+    /// be sent from a streaming command.
+    fn execute_streaming(
+        &self,
+        args: clap::ArgMatches,
+        stdin: Receiver<String>,
+        stdout: Sender<String>,
+        stderr: &mut dyn std::fmt::Write,
+    ) -> Result<(), StreamingError>;
+}
+
+/// A stage of a streaming pipeline.
+enum Stage<'scope> {
+    /// Running on its own thread, which returns the stage's result and how
+    /// long after the pipeline started it finished.
+    Running {
+        name: String,
+        stage: Option<usize>,
+        handle: ScopedJoinHandle<'scope, (Result<(), StreamingError>, Duration)>,
+    },
+    /// Run to completion on the console's thread.
+    Finished { name: String, elapsed: Duration },
+}
+
+/// Run `runnables`, passing each line of the pipeline's output to `sink` as
+/// soon as it reaches the end.
+///
+/// The output filter is applied to the output of each buffered stage, and to
+/// each line of the output of a streaming last stage.
+pub(crate) fn run_pipeline(
+    console: &mut Console,
+    runnables: VecDeque<Runnable>,
+    sink: &mut dyn FnMut(&str) -> Result<(), ConsoleError>,
+) -> Result<(), ConsoleError> {
+    let in_pipeline = runnables.len() > 1;
+    let start = Instant::now();
+
+    thread::scope(|scope| {
+        let mut stages = vec![];
+        // The first stage has no input
+        let (_, mut stdin) = mpsc::channel();
+        let mut last_streamed = false;
+        for (i, runnable) in runnables.into_iter().enumerate() {
+            let stage = in_pipeline.then_some(i + 1);
+            let (stdout, next_stdin) = mpsc::channel();
+            let runnable = match runnable {
+                Runnable::Command { cmd, args } => match cmd.as_streaming() {
+                    Some(streaming) => {
+                        let handle = scope.spawn(move || {
+                            let result =
+                                streaming.execute_streaming(args, stdin, stdout, &mut StderrWriter);
+                            (result, start.elapsed())
+                        });
+                        let name = cmd.get_name();
+                        stages.push(Stage::Running {
+                            name,
+                            stage,
+                            handle,
+                        });
+                        stdin = next_stdin;
+                        last_streamed = true;
+                        continue;
+                    }
+                    None => Runnable::Command { cmd, args },
+                },
+                runnable => runnable,
+            };
+
+            // Buffered stages wait for all their input, as usual
+            let input: String = stdin.iter().map(|line| line + "\n").collect();
+            let mut output = String::new();
+            let (result, name) = console.run_stage(runnable, &input, &mut output);
+            stages.push(Stage::Finished {
+                name: name.clone(),
+                elapsed: start.elapsed(),
+            });
+            let Some(output) = console.finish_stage(result, name, stage, output)? else {
+                return Ok(());
+            };
+            for line in output.lines() {
+                // The next stage may have stopped reading
+                let _ = stdout.send(line.to_string());
+            }
+            stdin = next_stdin;
+            last_streamed = false;
+        }
+
+        for line in stdin {
+            let line = if last_streamed {
+                console.filter_output(line)
+            } else {
+                line
+            };
+            sink(&line)?;
+        }
+
+        let mut timings = vec![];
+        for stage in stages {
+            let (name, elapsed) = match stage {
+                Stage::Running {
+                    name,
+                    stage,
+                    handle,
+                } => {
+                    let (result, elapsed) = handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+                    let result = match result {
+                        Err(e) if e.is::<SendError<String>>() => Ok(()),
+                        result => result.map_err(|e| -> Box<dyn Error> { e }),
+                    };
+                    console.finish_stage(result, name.clone(), stage, String::new())?;
+                    (name, elapsed)
+                }
+                Stage::Finished { name, elapsed } => (name, elapsed),
+            };
+            timings.push((name, elapsed));
+        }
+        if console.timing() {
+            report_timings(&timings);
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::{Command, ExecContext, Parsed};
+
+    /// Send the numbers from 1 to `count`.
+    struct Count;
+
+    impl Command for Count {
+        fn get_name(&self) -> String {
+            "count".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("count").arg(
+                clap::Arg::new("count")
+                    .value_parser(clap::value_parser!(u32))
+                    .required(true),
+            )
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            _ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn Error>> {
+            Err("only streaming is supported".into())
+        }
+
+        fn as_streaming(&self) -> Option<&dyn StreamingCommand> {
+            Some(self)
+        }
+    }
+
+    impl StreamingCommand for Count {
+        fn execute_streaming(
+            &self,
+            args: clap::ArgMatches,
+            _stdin: Receiver<String>,
+            stdout: Sender<String>,
+            _stderr: &mut dyn std::fmt::Write,
+        ) -> Result<(), StreamingError> {
+            for i in 1..=*args.get_one::<u32>("count").unwrap() {
+                stdout.send(i.to_string())?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Pass on only the first line of stdin.
+    struct First;
+
+    impl Command for First {
+        fn get_name(&self) -> String {
+            "first".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("first")
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn Error>> {
+            writeln!(
+                ctx.stdout,
+                "{}",
+                ctx.stdin.lines().next().unwrap_or_default()
+            )?;
+            Ok(())
+        }
+
+        fn as_streaming(&self) -> Option<&dyn StreamingCommand> {
+            Some(self)
+        }
+    }
+
+    impl StreamingCommand for First {
+        fn execute_streaming(
+            &self,
+            _args: clap::ArgMatches,
+            stdin: Receiver<String>,
+            stdout: Sender<String>,
+            _stderr: &mut dyn std::fmt::Write,
+        ) -> Result<(), StreamingError> {
+            if let Ok(line) = stdin.recv() {
+                stdout.send(line)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Reverse the order of the lines of stdin, without streaming.
+    struct Reverse;
+
+    impl Command for Reverse {
+        fn get_name(&self) -> String {
+            "reverse".to_string()
+        }
+
+        fn get_parser(&self) -> clap::Command {
+            clap::Command::new("reverse")
+        }
+
+        fn execute(
+            &self,
+            _args: clap::ArgMatches,
+            ctx: &mut ExecContext<'_>,
+        ) -> Result<(), Box<dyn Error>> {
+            for line in ctx.stdin_lines().rev() {
+                writeln!(ctx.stdout, "{line}")?;
+            }
+            Ok(())
+        }
+    }
+
+    fn run(console: &mut Console, line: &str) -> Result<Vec<String>, ConsoleError> {
+        let commands = console.command_set();
+        let command_set = commands.borrow();
+        let Parsed::Pipeline(runnables) = console.parse_pipeline(&command_set, line)? else {
+            panic!("`{line}` didn't parse");
+        };
+
+        let mut lines = vec![];
+        run_pipeline(console, runnables, &mut |line| {
+            lines.push(line.to_string());
+            Ok(())
+        })?;
+        Ok(lines)
+    }
+
+    #[test]
+    fn streams_between_buffered_stages() {
+        let mut console = Console::default()
+            .with_streaming(true)
+            .add_command(Box::new(Count))
+            .add_command(Box::new(First))
+            .add_command(Box::new(Reverse));
+
+        assert_eq!(run(&mut console, "count 3").unwrap(), ["1", "2", "3"]);
+        assert_eq!(
+            run(&mut console, "count 3 | reverse").unwrap(),
+            ["3", "2", "1"]
+        );
+        // `count` fails to send once `first` stops reading, which isn't an
+        // error
+        assert_eq!(run(&mut console, "count 100000 | first").unwrap(), ["1"]);
+    }
+}